@@ -0,0 +1,23 @@
+package com.luis.bci;
+
+// Filled in by CaesiumNative.analyzePic
+public class CCSImageInfo {
+    public String mime_type;
+    public int width;
+    public int height;
+    // EXIF orientation, 1 when absent
+    public int orientation;
+    public int bit_depth;
+    public String color_space;
+    // e.g. "4:2:0", null when not applicable
+    public String chroma_subsampling;
+    public boolean has_alpha;
+    public boolean uses_alpha;
+    // 0 if unknown or not a JPEG
+    public int jpeg_quality;
+    public int frame_count;
+    public int icc_size;
+    public int exif_size;
+    public int xmp_size;
+    public int other_metadata_size;
+}
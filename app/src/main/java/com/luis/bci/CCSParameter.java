@@ -1,15 +1,86 @@
 package com.luis.bci;
 
 public class CCSParameter {
-    public boolean keep_metadata;
+    // Metadata to carry over, each kind is kept or stripped on its own
+    public boolean keep_icc;
+    public boolean keep_orientation;
+    // EXIF tags not covered by the other switches: camera, exposure, dates, thumbnail
+    public boolean keep_exif;
+    public boolean keep_gps;
+    public boolean keep_serial_numbers;
+    public boolean keep_maker_notes;
+    public boolean keep_xmp;
+    public boolean keep_iptc;
+    // JPEG COM segments and PNG text chunks
+    public boolean keep_comments;
+    // Any other JPEG application segment or PNG ancillary chunk
+    public boolean keep_other_metadata;
     public int jpeg_qu;
     public ChromaSubsampling subsamp_mode;
+    // Optimize losslessly when jpeg_qu is not below the estimated source quality
+    public boolean avoid_generational_loss;
+    // Progressive scans with optimised scan layout, false writes baseline JPEGs
+    public boolean progressive = true;
+    // mozjpeg tuning, trellis and deringing only apply to the MaxCompression profile
+    public boolean trellis = true;
+    public boolean trellis_dc = true;
+    public boolean overshoot_deringing = true;
+    // JINT_BASE_QUANT_TBL_IDX, 0 is the JPEG Annex K table, 3 the mozjpeg default
+    public int quant_table = 3;
+    public CompressionProfile compression_profile = CompressionProfile.MaxCompression;
+    // Chroma quality, 0 uses jpeg_qu for both luma and chroma
+    public int jpeg_chroma_qu;
+    // Lossless rotation/flip of the DCT blocks, trim drops partial edge MCUs that cannot be moved
+    public Transform transform = Transform.None;
+    public boolean trim = true;
+    // Crop of JPEGs in output coordinates, a zero width or height disables it. Lossless crops move
+    // the top left corner to the enclosing MCU boundary, conversions to another format crop exactly
+    public int crop_x;
+    public int crop_y;
+    public int crop_width;
+    public int crop_height;
+    // Drop the chroma of colour JPEGs, Auto only does so when it carries no visible colour
+    public Grayscale grayscale = Grayscale.Off;
+    // Re-encode CMYK/YCCK JPEGs as RGB, through their ICC profile when they have one
+    public boolean cmyk_to_rgb;
     public int png_qu;
     public boolean png_force_zopfli;
     public int webp_qu;
     public boolean optm;
     public int width;
     public int height;
+    // How the image fits a box when both width and height are set: Fill stretches, Contain fits inside,
+    // Cover fills and crops the centre, Outside covers without cropping, Pad fits inside and pads to the box
+    public ResizeFit resize_fit = ResizeFit.Fill;
+    // Box uses width/height, the others scale the whole image: the longer or shorter edge to
+    // resize_target_value pixels, the area to resize_target_value megapixels, or by resize_target_value percent
+    public ResizeTarget resize_target = ResizeTarget.Box;
+    public double resize_target_value;
+    // Never scale up, images the target would enlarge keep their size
+    public boolean without_enlargement;
+    // Resampling filter, from fastest to sharpest: Nearest, Triangle, CatmullRom, Mitchell (softer), Lanczos3
+    public ResizeFilter resize_filter = ResizeFilter.Lanczos3;
+    // Resample in linear light rather than on sRGB values, fine detail and thin lines keep their brightness
+    public boolean linear_resize;
+    // Maximum output size in bytes, 0 disables the limit
+    public int output_size;
+    // Maximum DSSIM from the original, 0 disables it. For a minimum SSIM use 1 / ssim - 1
+    public double max_dssim;
+    public OutputFormat output_format = OutputFormat.Original;
+    // 0xRRGGBB background for transparent pixels when converting to JPEG, and for Pad without alpha
+    public int matte_color = 0xFFFFFF;
+    // Rotate/flip according to the EXIF orientation and reset the tag to 1
+    public boolean auto_orient;
+    // Convert pixels with an embedded ICC profile (Display P3, Adobe RGB, CMYK...) to sRGB and drop the profile
+    public boolean convert_to_srgb;
+
+    // Set by the native side after compressPic, the libjpeg quality of a JPEG source or 0 if unknown
+    public int source_jpeg_quality;
+    // Set by the native side after compressPic, the crop that was applied (MCU aligned unless converted) or all 0
+    public int applied_crop_x;
+    public int applied_crop_y;
+    public int applied_crop_width;
+    public int applied_crop_height;
 
     public enum ChromaSubsampling {
         CS444,
@@ -19,9 +90,62 @@ public class CCSParameter {
         Auto
     }
 
+    public enum CompressionProfile {
+        MaxCompression,
+        Fastest
+    }
+
+    public enum Transform {
+        None,
+        FlipHorizontal,
+        FlipVertical,
+        Transpose,
+        Transverse,
+        Rotate90,
+        Rotate180,
+        Rotate270
+    }
+
+    public enum Grayscale {
+        Off,
+        On,
+        Auto
+    }
+
+    public enum ResizeTarget {
+        Box,
+        LongEdge,
+        ShortEdge,
+        Megapixels,
+        Percentage
+    }
+
+    public enum ResizeFilter {
+        Nearest,
+        Triangle,
+        CatmullRom,
+        Mitchell,
+        Lanczos3
+    }
+
+    public enum ResizeFit {
+        Fill,
+        Contain,
+        Cover,
+        Outside,
+        Pad
+    }
+
+    public enum OutputFormat {
+        Original,
+        Jpeg,
+        Png,
+        WebP
+    }
+
     public CCSParameter(boolean keep_metadata, int jpeg_qu, ChromaSubsampling subsamp_mode, int png_qu,
             boolean png_force_zopfli, int webp_qu, boolean optm, int width, int height) {
-        this.keep_metadata = keep_metadata;
+        setKeepMetadata(keep_metadata);
         this.jpeg_qu = jpeg_qu;
         this.subsamp_mode = subsamp_mode;
         this.png_qu = png_qu;
@@ -32,4 +156,17 @@ public class CCSParameter {
         this.height = height;
     }
 
+    public void setKeepMetadata(boolean keep) {
+        keep_icc = keep;
+        keep_orientation = keep;
+        keep_exif = keep;
+        keep_gps = keep;
+        keep_serial_numbers = keep;
+        keep_maker_notes = keep;
+        keep_xmp = keep;
+        keep_iptc = keep;
+        keep_comments = keep;
+        keep_other_metadata = keep;
+    }
+
 }
\ No newline at end of file
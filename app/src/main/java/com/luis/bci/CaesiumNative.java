@@ -6,5 +6,6 @@ public class CaesiumNative {
 		System.loadLibrary("caesium_jni");
 	}
 	public static native byte[] compressPic(byte[] inData, CCSParameter conf);
+	public static native CCSImageInfo analyzePic(byte[] inData);
 
 }
\ No newline at end of file
@@ -79,17 +79,6 @@ class MainActivity : Activity() {
         }
     }
 
-    private fun pngIsTransparent(bmp: Bitmap) : Boolean {
-        for (y in 0 until bmp.height) {
-            for(x in 0 until bmp.width) {
-                val pix = bmp.getPixel(x, y).toLong()
-                // Found transparency
-                if((pix and 0xff000000) != 0xff000000) return true
-            }
-        }
-        return false
-    }
-
     private suspend fun processFiles(folder: DocumentFile) = withContext(Dispatchers.IO) {
         folder.listFiles().map { file ->
             async {
@@ -98,7 +87,8 @@ class MainActivity : Activity() {
 
                 try {
                     when (fileExtension) {
-                        "png", "bmp", "webp" -> processImageFile(file, fileExtension, folder)
+                        "png", "webp" -> processConvertibleFile(file, fileExtension, folder)
+                        "bmp" -> processImageFile(file, fileExtension, folder)
                         "jpg", "jpeg" -> processJpgFile(file)
                     }
                 } catch (e: Exception) {
@@ -111,11 +101,33 @@ class MainActivity : Activity() {
     private fun processImageFile(file: DocumentFile, fileExtension: String, origFolder: DocumentFile) {
         contentResolver.openInputStream(file.uri)?.use { inputStream ->
             BitmapFactory.decodeStream(inputStream)?.also { bitmap ->
-                if (fileExtension != "png" || !pngIsTransparent(bitmap)) {
-                    file.name?.let {
-                        createConvertedJPEG(file, origFolder,
-                            it, fileExtension, bitmap)
+                file.name?.let {
+                    createConvertedJPEG(file, origFolder,
+                        it, fileExtension, bitmap)
+                }
+            }
+        }
+    }
+
+    private fun processConvertibleFile(file: DocumentFile, fileExtension: String, origFolder: DocumentFile) {
+        contentResolver.openInputStream(file.uri)?.use { inputStream ->
+            val data = inputStream.readBytes()
+            // Transparent pixels are flattened onto the default white matte
+            val csp = CCSParameter(true, compRate, CCSParameter.ChromaSubsampling.Auto,
+                compRate, false, compRate, false, 0, 0)
+            csp.output_format = CCSParameter.OutputFormat.Jpeg
+            csp.auto_orient = true
+            // Location and device identifiers never leave the phone, colour profiles stay
+            csp.keep_gps = false
+            csp.keep_serial_numbers = false
+            CaesiumNative.compressPic(data, csp)?.let { convertedData ->
+                val fileName = file.name ?: return
+                val newFile = origFolder.createFile("image/jpeg", fileName.removeSuffix(".$fileExtension") + ".jpg")
+                newFile?.uri?.let { newUri ->
+                    contentResolver.openOutputStream(newUri)?.use { outputStream ->
+                        outputStream.write(convertedData)
                     }
+                    file.delete()
                 }
             }
         }
@@ -137,14 +149,22 @@ class MainActivity : Activity() {
             // Read all bytes from the inputStream at once
             val data = inputStream.readBytes()
             // Now you can process this data
-            val imgInfo = FastImageInfo(data)
-            compressAndReplaceJPG(file, data, imgInfo)
+            compressAndReplaceJPG(file, data)
         }
     }
 
-    private fun compressAndReplaceJPG(file: DocumentFile, data: ByteArray, imgInfo: FastImageInfo) {
+    private fun compressAndReplaceJPG(file: DocumentFile, data: ByteArray) {
+        // No target size, auto-orienting swaps the axes of rotated photos on its own
         val csp = CCSParameter(true, compRate, CCSParameter.ChromaSubsampling.Auto,
-            compRate, false, compRate, true, imgInfo.width, imgInfo.height)
+            compRate, false, compRate, false, 0, 0)
+        // Photos saved at or below compRate are only optimized losslessly instead of re-encoded
+        csp.avoid_generational_loss = true
+        csp.auto_orient = true
+        // Rotating losslessly must not cut the partial edge blocks off
+        csp.trim = false
+        // Location and device identifiers never leave the phone, colour profiles stay
+        csp.keep_gps = false
+        csp.keep_serial_numbers = false
         CaesiumNative.compressPic(data, csp)?.let { compressedData ->
             contentResolver.openFileDescriptor(file.uri, "rw")?.use { parcelFileDescriptor ->
                 val fileDescriptor = parcelFileDescriptor.fileDescriptor
@@ -0,0 +1,84 @@
+use image::DynamicImage;
+use qcms::{DataType, Intent, Profile, Transform};
+
+use crate::icc::is_srgb;
+
+/// Interleaved 8 bit sample layouts that can be converted to sRGB.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Layout {
+    Rgb,
+    Rgba,
+}
+
+/// Colour space signature of an ICC profile, from its header.
+fn color_space(icc: &[u8]) -> Option<&[u8]> {
+    icc.get(16..20)
+}
+
+fn srgb() -> Box<Profile> {
+    let mut srgb = Profile::new_sRGB();
+    srgb.precache_output_transform();
+    srgb
+}
+
+/// Converts RGB or RGBA samples tagged with the `icc` profile to sRGB in place, alpha is left alone.
+/// Returns false when the profile is not an RGB profile qcms can use, the samples are then untouched
+/// and the profile, if the metadata policy keeps it, stays with them.
+pub fn to_srgb(icc: &[u8], buffer: &mut [u8], layout: Layout) -> bool {
+    if is_srgb(icc) {
+        return true;
+    }
+    let data_type = match layout {
+        Layout::Rgb => DataType::RGB8,
+        Layout::Rgba => DataType::RGBA8,
+    };
+    let transform = if color_space(icc) == Some(b"RGB ") {
+        Profile::new_from_slice(icc, false)
+            .and_then(|profile| Transform::new(&profile, &srgb(), data_type, Intent::default()))
+    } else {
+        None
+    };
+
+    match transform {
+        Some(transform) => {
+            transform.apply(buffer);
+            true
+        }
+        None => {
+            log::info!("Cannot convert from the {} byte ICC profile, leaving the pixels as they are", icc.len());
+            false
+        }
+    }
+}
+
+/// Converts CMYK samples tagged with the `icc` profile to sRGB samples, `None` when the profile
+/// is not a usable CMYK profile. The samples follow the ICC convention, 0 is no ink.
+pub fn cmyk_to_srgb(icc: &[u8], cmyk: &[u8]) -> Option<Vec<u8>> {
+    if color_space(icc) != Some(b"CMYK") {
+        return None;
+    }
+    let profile = Profile::new_from_slice(icc, false)?;
+    let transform = Transform::new_to(&profile, &srgb(), DataType::CMYK, DataType::RGB8, Intent::default())?;
+
+    let mut rgb = vec![0; cmyk.len() / 4 * 3];
+    transform.convert(cmyk, &mut rgb);
+    Some(rgb)
+}
+
+/// Converts decoded pixels tagged with the `icc` profile to sRGB, see [`to_srgb`].
+pub fn image_to_srgb(icc: &[u8], image: &mut DynamicImage) -> bool {
+    if image.color().has_alpha() {
+        let mut rgba = image.to_rgba8();
+        if !to_srgb(icc, &mut rgba, Layout::Rgba) {
+            return false;
+        }
+        *image = DynamicImage::ImageRgba8(rgba);
+    } else {
+        let mut rgb = image.to_rgb8();
+        if !to_srgb(icc, &mut rgb, Layout::Rgb) {
+            return false;
+        }
+        *image = DynamicImage::ImageRgb8(rgb);
+    }
+    true
+}
@@ -0,0 +1,235 @@
+use std::{io, mem};
+
+use image::DynamicImage;
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::png::{Png, PngChunk};
+use img_parts::Bytes;
+
+use crate::resize::resize_image;
+use crate::utils::SupportedFileTypes;
+use crate::{color, exif, icc, jpeg, png, webp, CSParameters};
+use crate::jpeg::Crop;
+use crate::orientation::Transform;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Original,
+    Jpeg,
+    Png,
+    WebP,
+}
+
+/// Decodes `in_file` and encodes it as `output_type` with that format's encoder and settings.
+/// Returns the crop rectangle applied to a JPEG source, exact since the pixels are re-encoded anyway.
+pub fn convert(
+    in_file: Vec<u8>,
+    input_type: SupportedFileTypes,
+    output_type: SupportedFileTypes,
+    out_buffer: &mut Vec<u8>,
+    parameters: &CSParameters,
+) -> Result<Option<Crop>, io::Error> {
+    // Other formats have no CMYK, the pixels become sRGB
+    let cmyk = input_type == SupportedFileTypes::Jpeg && jpeg::is_cmyk(&in_file);
+    let mut image = if cmyk { jpeg::decode_cmyk(&in_file)? } else { decode(&in_file, input_type)? };
+    let source = (image.width(), image.height());
+    // The JPEG options apply to JPEG sources, as they do when the format is kept
+    let jpeg_input = input_type == SupportedFileTypes::Jpeg;
+    let grayscale = jpeg_input && jpeg::drops_chroma(&in_file, parameters.jpeg.grayscale);
+    let xmp = if parameters.metadata.xmp { xmp(&in_file, input_type) } else { None };
+    let (mut iccp, mut exif) = if input_type == SupportedFileTypes::WebP {
+        let metadata = webp::Metadata::read(&in_file);
+        (metadata.icc.map(Into::into), metadata.exif.map(Into::into))
+    } else if parameters.metadata.keeps_any() || parameters.auto_orient || parameters.convert_to_srgb {
+        jpeg::extract_metadata(in_file)?
+    } else {
+        (None, None)
+    };
+    if cmyk {
+        iccp = None;
+    }
+    if parameters.convert_to_srgb {
+        if let Some(icc) = iccp.as_deref().filter(|icc| !icc::is_srgb(icc)) {
+            if color::image_to_srgb(icc, &mut image) {
+                iccp = None;
+            }
+        }
+    }
+    let mut transform = Transform::None;
+    if parameters.auto_orient {
+        if let Some(data) = &exif {
+            let mut data = data.to_vec();
+            if let Some(orientation) = exif::reset_orientation(&mut data) {
+                transform = Transform::from_orientation(orientation);
+                exif = Some(data.into());
+            }
+        }
+    }
+    if jpeg_input {
+        transform = transform.then(parameters.jpeg.transform);
+    }
+    if transform != Transform::None {
+        image = transform.apply(image);
+        exif = exif.map(|data| exif::remove_thumbnail(&data).into());
+    }
+    if grayscale {
+        image = DynamicImage::ImageLuma8(image.to_luma8());
+    }
+    let mut applied_crop = None;
+    if let Some(crop) = parameters.jpeg.crop.filter(|_| jpeg_input) {
+        if crop.x >= image.width() || crop.y >= image.height() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Crop rectangle is outside the image"));
+        }
+        let width = crop.width.min(image.width() - crop.x);
+        let height = crop.height.min(image.height() - crop.y);
+        image = image.crop_imm(crop.x, crop.y, width, height);
+        applied_crop = Some(Crop { x: crop.x, y: crop.y, width, height });
+    }
+    image = resize_image(image, parameters)?;
+
+    let mut converted = match output_type {
+        SupportedFileTypes::Jpeg => jpeg::compress_image(&image, parameters)?,
+        SupportedFileTypes::Png => png::compress_image(&image, parameters)?,
+        SupportedFileTypes::WebP => webp::compress_image(&image, parameters)?,
+        SupportedFileTypes::Unkn => {
+            return Err(io::Error::new(io::ErrorKind::Other, "Unsupported output format"))
+        }
+    };
+
+    if parameters.metadata.keeps_any() {
+        let (iccp, exif) = parameters.metadata.filter((iccp, exif));
+        converted = if output_type == SupportedFileTypes::WebP {
+            let metadata = webp::Metadata {
+                icc: iccp.map(|i| i.to_vec()),
+                exif: exif.map(|e| e.to_vec()),
+                xmp,
+            };
+            webp::write_metadata(converted, &metadata, source)
+        } else {
+            let converted = jpeg::save_metadata(converted, iccp, exif, source);
+            match xmp {
+                Some(xmp) => write_xmp(converted, output_type, &xmp),
+                None => converted,
+            }
+        };
+    }
+
+    let _ = mem::replace(out_buffer, converted);
+    Ok(applied_crop)
+}
+
+/// XMP packet of a JPEG APP1 segment, an uncompressed PNG iTXt chunk or a WebP XMP chunk.
+fn xmp(in_file: &[u8], input_type: SupportedFileTypes) -> Option<Vec<u8>> {
+    match input_type {
+        SupportedFileTypes::Jpeg => jpeg::segments(in_file)
+            .into_iter()
+            .find_map(|(marker, payload)| match marker {
+                0xE1 => payload.strip_prefix(b"http://ns.adobe.com/xap/1.0/\0"),
+                _ => None,
+            })
+            .map(|xmp| xmp.to_vec()),
+        SupportedFileTypes::Png => png::chunks(in_file)
+            .into_iter()
+            .filter(|(chunk_type, _)| chunk_type == b"iTXt")
+            .find_map(|(_, data)| {
+                // Compression flag and method, then the language tag and translated keyword
+                let data = data.strip_prefix(b"XML:com.adobe.xmp\0\0\0")?;
+                let mut fields = data.splitn(3, |b| *b == 0);
+                fields.next()?;
+                fields.next()?;
+                fields.next().map(|xmp| xmp.to_vec())
+            }),
+        SupportedFileTypes::WebP => webp::Metadata::read(in_file).xmp,
+        SupportedFileTypes::Unkn => None,
+    }
+}
+
+/// Adds `xmp` as a JPEG APP1 segment after the other application segments, or as an
+/// uncompressed PNG iTXt chunk before the image data. The file is returned as is if it cannot be parsed.
+fn write_xmp(converted: Vec<u8>, output_type: SupportedFileTypes, xmp: &[u8]) -> Vec<u8> {
+    let mut with_xmp = vec![];
+    let written = match output_type {
+        SupportedFileTypes::Jpeg => Jpeg::from_bytes(Bytes::copy_from_slice(&converted)).ok().map(|mut jpeg| {
+            let contents = [b"http://ns.adobe.com/xap/1.0/\0", xmp].concat();
+            let segments = jpeg.segments_mut();
+            let position = segments.iter().position(|s| !(0xE0..=0xEF).contains(&s.marker())).unwrap_or(segments.len());
+            segments.insert(position, JpegSegment::new_with_contents(markers::APP1, contents.into()));
+            jpeg.encoder().write_to(&mut with_xmp)
+        }),
+        SupportedFileTypes::Png => Png::from_bytes(Bytes::copy_from_slice(&converted)).ok().map(|mut png| {
+            // Uncompressed, with empty language tag and translated keyword
+            let contents = [b"XML:com.adobe.xmp\0\0\0\0\0", xmp].concat();
+            let chunks = png.chunks_mut();
+            let position = chunks.iter().position(|c| c.kind() == *b"IDAT").unwrap_or(chunks.len());
+            chunks.insert(position, PngChunk::new(*b"iTXt", contents.into()));
+            png.encoder().write_to(&mut with_xmp)
+        }),
+        _ => None,
+    };
+    match written {
+        Some(Ok(_)) => with_xmp,
+        _ => converted,
+    }
+}
+
+fn decode(in_file: &[u8], input_type: SupportedFileTypes) -> Result<DynamicImage, io::Error> {
+    if input_type == SupportedFileTypes::WebP {
+        return webp::decode(in_file);
+    }
+
+    match image::load_from_memory(in_file) {
+        Ok(i) => Ok(i),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    }
+}
+
+#[test]
+fn jpeg_options_apply_to_converted_jpegs() {
+    let mut source = vec![];
+    let pixels = image::RgbImage::from_fn(32, 16, |x, _| image::Rgb([x as u8 * 8, 120, 200]));
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut source, 90).encode_image(&pixels).unwrap();
+    let mut parameters = crate::initialize_parameters();
+    parameters.jpeg.transform = Transform::Rotate90;
+    parameters.jpeg.grayscale = crate::Grayscale::On;
+    let mut out_buffer = vec![];
+    convert(source, SupportedFileTypes::Jpeg, SupportedFileTypes::Png, &mut out_buffer, &parameters).unwrap();
+
+    let converted = image::load_from_memory(&out_buffer).unwrap();
+    assert_eq!((converted.width(), converted.height()), (16, 32));
+    assert!(converted.to_rgb8().pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+}
+
+#[test]
+fn crop_applies_to_converted_jpegs() {
+    let mut source = vec![];
+    let pixels = image::RgbImage::from_pixel(32, 16, image::Rgb([40, 120, 200]));
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut source, 90).encode_image(&pixels).unwrap();
+    let mut parameters = crate::initialize_parameters();
+    parameters.jpeg.crop = Some(Crop { x: 3, y: 5, width: 100, height: 4 });
+    let mut out_buffer = vec![];
+    let applied_crop =
+        convert(source, SupportedFileTypes::Jpeg, SupportedFileTypes::WebP, &mut out_buffer, &parameters).unwrap();
+
+    assert_eq!(applied_crop, Some(Crop { x: 3, y: 5, width: 29, height: 4 }));
+    let converted = image::load_from_memory(&out_buffer).unwrap();
+    assert_eq!((converted.width(), converted.height()), (29, 4));
+}
+
+#[test]
+fn xmp_is_kept_for_every_output() {
+    let packet = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+    let mut source = vec![];
+    let pixels = image::RgbImage::from_pixel(8, 8, image::Rgb([40, 120, 200]));
+    image::DynamicImage::ImageRgb8(pixels).write_to(&mut io::Cursor::new(&mut source), image::ImageFormat::Png).unwrap();
+    let source = write_xmp(source, SupportedFileTypes::Png, packet);
+    let mut parameters = crate::initialize_parameters();
+    parameters.metadata.xmp = true;
+    for output_type in [SupportedFileTypes::Jpeg, SupportedFileTypes::WebP] {
+        let mut out_buffer = vec![];
+        convert(source.clone(), SupportedFileTypes::Png, output_type, &mut out_buffer, &parameters).unwrap();
+        assert_eq!(xmp(&out_buffer, output_type).as_deref(), Some(&packet[..]));
+
+        let mut png = vec![];
+        convert(out_buffer, output_type, SupportedFileTypes::Png, &mut png, &parameters).unwrap();
+        assert_eq!(xmp(&png, SupportedFileTypes::Png).as_deref(), Some(&packet[..]));
+    }
+}
@@ -0,0 +1,527 @@
+use crate::MetadataPolicy;
+
+const ORIENTATION: u16 = 0x0112;
+const XMP: u16 = 0x02BC;
+const IPTC: u16 = 0x83BB;
+const ICC_PROFILE: u16 = 0x8773;
+const MAKER_NOTE: u16 = 0x927C;
+const BODY_SERIAL_NUMBER: u16 = 0xA431;
+const LENS_SERIAL_NUMBER: u16 = 0xA435;
+const CAMERA_SERIAL_NUMBER: u16 = 0xC62F;
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+const INTEROP_IFD: u16 = 0xA005;
+const PIXEL_X_DIMENSION: u16 = 0xA002;
+const PIXEL_Y_DIMENSION: u16 = 0xA003;
+const THUMBNAIL_OFFSET: u16 = 0x0201;
+const THUMBNAIL_LENGTH: u16 = 0x0202;
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+/// Read-only view over the TIFF structure of an EXIF blob.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn parse(exif: &'a [u8]) -> Option<Tiff<'a>> {
+        // JPEG APP1 payloads and some WebP/PNG writers prefix the TIFF header
+        let data = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+        let big_endian = match data.get(0..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None,
+        };
+
+        Some(Tiff { data, big_endian })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset.checked_add(2)?)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn encode_u16(&self, value: u16) -> [u8; 2] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn encode_u32(&self, value: u32) -> [u8; 4] {
+        if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    /// Offsets of the 12 byte entries of an IFD.
+    fn entries(&self, ifd_offset: usize) -> Vec<usize> {
+        let count = self.u16(ifd_offset).unwrap_or(0) as usize;
+        (0..count)
+            .map_while(|i| ifd_offset.checked_add(2 + i * 12))
+            .take_while(|entry| entry.checked_add(12).is_some_and(|end| end <= self.data.len()))
+            .collect()
+    }
+
+    /// Offset of the 12 byte IFD entry with the given tag.
+    fn find_entry(&self, ifd_offset: usize, tag: u16) -> Option<usize> {
+        self.entries(ifd_offset)
+            .into_iter()
+            .find(|entry| self.u16(*entry) == Some(tag))
+    }
+
+    /// Offset of the IFD a pointer tag such as `EXIF_IFD` refers to.
+    fn sub_ifd(&self, ifd_offset: usize, tag: u16) -> Option<usize> {
+        let entry = self.find_entry(ifd_offset, tag)?;
+        match self.u32(entry + 8)? as usize {
+            0 => None,
+            offset => Some(offset),
+        }
+    }
+
+    /// Position of the link to the next IFD, right after the entries.
+    fn next_ifd_link(&self, ifd_offset: usize) -> Option<usize> {
+        let link = ifd_offset.checked_add(2 + self.u16(ifd_offset)? as usize * 12)?;
+        if link.checked_add(4)? <= self.data.len() {
+            Some(link)
+        } else {
+            None
+        }
+    }
+
+    /// Integer value of a SHORT or LONG entry.
+    fn value(&self, entry: usize) -> Option<u32> {
+        match self.u16(entry + 2)? {
+            SHORT => Some(self.u16(entry + 8)? as u32),
+            LONG => self.u32(entry + 8),
+            _ => None,
+        }
+    }
+
+    /// Bytes taken by the value of an entry, stored out of line when above 4. None when the count
+    /// is too large to address.
+    fn value_size(&self, entry: usize) -> Option<usize> {
+        let type_size: usize = match self.u16(entry + 2) {
+            Some(1 | 2 | 6 | 7) => 1,
+            Some(3 | 8) => 2,
+            Some(4 | 9 | 11) => 4,
+            Some(5 | 10 | 12) => 8,
+            _ => 0,
+        };
+        type_size.checked_mul(self.u32(entry + 4).unwrap_or(0) as usize)
+    }
+
+    /// End of the data used by IFD0 and its Exif, Interop and GPS IFDs.
+    fn used_end(&self, ifd0: usize) -> Option<usize> {
+        let mut ifds = vec![ifd0];
+        if let Some(exif_ifd) = self.sub_ifd(ifd0, EXIF_IFD) {
+            ifds.push(exif_ifd);
+            ifds.extend(self.sub_ifd(exif_ifd, INTEROP_IFD));
+        }
+        ifds.extend(self.sub_ifd(ifd0, GPS_IFD));
+        ifds.into_iter().try_fold(0, |end, ifd| Some(end.max(self.extent(ifd)?.1)))
+    }
+
+    /// Drops the entries `keep` rejects from `out`, a copy of the data, zeroing their values and
+    /// the IFDs they point to. Returns how many entries are left.
+    fn retain(&self, out: &mut [u8], ifd_offset: usize, keep: impl Fn(u16) -> bool) -> usize {
+        let entries = self.entries(ifd_offset);
+        let next_ifd = self.next_ifd_link(ifd_offset).and_then(|link| self.u32(link)).unwrap_or(0);
+        let mut kept = vec![];
+        for entry in &entries {
+            if keep(self.u16(*entry).unwrap_or(0)) {
+                kept.push(out[*entry..*entry + 12].to_vec());
+            } else {
+                self.clear_value(out, *entry);
+                if matches!(self.u16(*entry), Some(EXIF_IFD | GPS_IFD | INTEROP_IFD)) {
+                    if let Some(sub_ifd) = self.value(*entry).filter(|offset| *offset != 0) {
+                        self.clear_ifd(out, sub_ifd as usize);
+                    }
+                }
+            }
+        }
+        if kept.len() == entries.len() {
+            return kept.len();
+        }
+
+        let end = (ifd_offset + 2 + entries.len() * 12 + 4).min(out.len());
+        out[ifd_offset..end].fill(0);
+        out[ifd_offset..ifd_offset + 2].copy_from_slice(&self.encode_u16(kept.len() as u16));
+        for (i, entry) in kept.iter().enumerate() {
+            let position = ifd_offset + 2 + i * 12;
+            out[position..position + 12].copy_from_slice(entry);
+        }
+        let link = ifd_offset + 2 + kept.len() * 12;
+        if link + 4 <= out.len() {
+            out[link..link + 4].copy_from_slice(&self.encode_u32(next_ifd));
+        }
+
+        kept.len()
+    }
+
+    /// Zeroes an IFD and the values it stores out of line, without following its pointers.
+    fn clear_ifd(&self, out: &mut [u8], ifd_offset: usize) {
+        let entries = self.entries(ifd_offset);
+        for entry in &entries {
+            self.clear_value(out, *entry);
+        }
+        let end = ifd_offset.saturating_add(2 + entries.len() * 12 + 4).min(out.len());
+        if ifd_offset < end {
+            out[ifd_offset..end].fill(0);
+        }
+    }
+
+    fn clear_value(&self, out: &mut [u8], entry: usize) {
+        let size = match self.value_size(entry) {
+            Some(size) if size > 4 => size,
+            _ => return,
+        };
+        if let Some(offset) = self.u32(entry + 8) {
+            let offset = offset as usize;
+            if let Some(end) = offset.checked_add(size).filter(|end| *end <= out.len()) {
+                out[offset..end].fill(0);
+            }
+        }
+    }
+
+    /// Start and end of the IFD together with the values it stores out of line. None when an end
+    /// is past what can be addressed.
+    fn extent(&self, ifd_offset: usize) -> Option<(usize, usize)> {
+        let entries = self.entries(ifd_offset);
+        let mut start = ifd_offset;
+        let mut end = ifd_offset.checked_add(2 + entries.len() * 12 + 4)?;
+        for entry in entries {
+            let size = self.value_size(entry)?;
+            if size > 4 {
+                if let Some(offset) = self.u32(entry + 8) {
+                    start = start.min(offset as usize);
+                    end = end.max((offset as usize).checked_add(size)?);
+                }
+            }
+        }
+
+        Some((start, end))
+    }
+}
+
+/// EXIF orientation (1-8) stored in IFD0, if any.
+pub fn orientation(exif: &[u8]) -> Option<u16> {
+    let tiff = Tiff::parse(exif)?;
+    let ifd0 = tiff.u32(4)? as usize;
+    let entry = tiff.find_entry(ifd0, ORIENTATION)?;
+    let orientation = tiff.u16(entry + 8)?;
+
+    if (1..=8).contains(&orientation) {
+        Some(orientation)
+    } else {
+        None
+    }
+}
+
+/// Sets the orientation tag to 1 in place and returns the previous orientation, if any.
+pub fn reset_orientation(exif: &mut [u8]) -> Option<u16> {
+    let previous = orientation(exif)?;
+    let tiff = Tiff::parse(exif)?;
+    let ifd0 = tiff.u32(4)? as usize;
+    let entry = tiff.find_entry(ifd0, ORIENTATION)?;
+    // The SHORT value sits left aligned in the 4 byte value field
+    let value = if tiff.big_endian { [0, 1] } else { [1, 0] };
+    let start = exif.len() - tiff.data.len() + entry + 8;
+    exif[start..start + 2].copy_from_slice(&value);
+
+    Some(previous)
+}
+
+/// Rewrites PixelXDimension/PixelYDimension for an image of `width` x `height`. When they change or
+/// the image is no longer the `source` size the EXIF was written for, the IFD1 thumbnail no longer
+/// matches the image and is removed.
+pub fn update_dimensions(exif: &[u8], source: (u32, u32), width: u32, height: u32) -> Vec<u8> {
+    let mut updated = exif.to_vec();
+    let tiff = match Tiff::parse(exif) {
+        Some(t) => t,
+        None => return updated,
+    };
+    let prefix = exif.len() - tiff.data.len();
+    let exif_ifd = tiff.u32(4).and_then(|ifd0| tiff.sub_ifd(ifd0 as usize, EXIF_IFD));
+
+    let mut changed = source != (width, height);
+    for (tag, dimension) in [(PIXEL_X_DIMENSION, width), (PIXEL_Y_DIMENSION, height)] {
+        let entry = match exif_ifd.and_then(|ifd| tiff.find_entry(ifd, tag)) {
+            Some(e) => e,
+            None => continue,
+        };
+        if tiff.value(entry) == Some(dimension) {
+            continue;
+        }
+        changed = true;
+
+        let position = prefix + entry;
+        if dimension <= u16::MAX as u32 && tiff.u16(entry + 2) == Some(SHORT) {
+            updated[position + 8..position + 12].copy_from_slice(&[0; 4]);
+            updated[position + 8..position + 10].copy_from_slice(&tiff.encode_u16(dimension as u16));
+        } else {
+            // Either type is allowed, larger images need a LONG
+            updated[position + 2..position + 4].copy_from_slice(&tiff.encode_u16(LONG));
+            updated[position + 4..position + 8].copy_from_slice(&tiff.encode_u32(1));
+            updated[position + 8..position + 12].copy_from_slice(&tiff.encode_u32(dimension));
+        }
+    }
+
+    if changed {
+        remove_thumbnail(&updated)
+    } else {
+        updated
+    }
+}
+
+/// Unlinks IFD1 and its thumbnail, dropping their bytes when nothing else is stored after them.
+pub fn remove_thumbnail(exif: &[u8]) -> Vec<u8> {
+    let mut updated = exif.to_vec();
+    let tiff = match Tiff::parse(exif) {
+        Some(t) => t,
+        None => return updated,
+    };
+    let prefix = exif.len() - tiff.data.len();
+    let ifd0 = match tiff.u32(4) {
+        Some(offset) => offset as usize,
+        None => return updated,
+    };
+    let link = match tiff.next_ifd_link(ifd0) {
+        Some(l) => l,
+        None => return updated,
+    };
+    let ifd1 = match tiff.u32(link) {
+        Some(0) | None => return updated,
+        Some(offset) => offset as usize,
+    };
+    updated[prefix + link..prefix + link + 4].copy_from_slice(&[0; 4]);
+
+    let mut thumbnail_start = match tiff.extent(ifd1) {
+        Some((start, _)) => start,
+        None => return updated,
+    };
+    if let Some(offset) = tiff.find_entry(ifd1, THUMBNAIL_OFFSET).and_then(|e| tiff.value(e)) {
+        if tiff.find_entry(ifd1, THUMBNAIL_LENGTH).is_some() {
+            thumbnail_start = thumbnail_start.min(offset as usize);
+        }
+    }
+    if tiff.used_end(ifd0).is_some_and(|end| end <= thumbnail_start) && thumbnail_start < tiff.data.len() {
+        updated.truncate(prefix + thumbnail_start);
+    }
+
+    updated
+}
+
+/// Removes the tags `policy` strips, zeroing their values. Returns None when no tag is left.
+pub fn filter(exif: &[u8], policy: &MetadataPolicy) -> Option<Vec<u8>> {
+    if policy.keeps_all_exif() && policy.icc && policy.xmp && policy.iptc {
+        return Some(exif.to_vec());
+    }
+
+    let tiff = Tiff::parse(exif)?;
+    let prefix = exif.len() - tiff.data.len();
+    let ifd0 = tiff.u32(4)? as usize;
+    let mut filtered = exif.to_vec();
+
+    let exif_ifd_kept = match tiff.sub_ifd(ifd0, EXIF_IFD) {
+        Some(exif_ifd) => tiff.retain(&mut filtered[prefix..], exif_ifd, |tag| match tag {
+            MAKER_NOTE => policy.maker_notes,
+            BODY_SERIAL_NUMBER | LENS_SERIAL_NUMBER => policy.serial_numbers,
+            _ => policy.exif,
+        }),
+        None => 0,
+    };
+    let kept = tiff.retain(&mut filtered[prefix..], ifd0, |tag| match tag {
+        ORIENTATION => policy.orientation,
+        EXIF_IFD => exif_ifd_kept > 0,
+        GPS_IFD => policy.gps,
+        CAMERA_SERIAL_NUMBER => policy.serial_numbers,
+        XMP => policy.xmp,
+        IPTC => policy.iptc,
+        ICC_PROFILE => policy.icc,
+        _ => policy.exif,
+    });
+    if kept == 0 {
+        return None;
+    }
+
+    if !policy.exif {
+        // The thumbnail goes with the rest of the EXIF, after that only zeroes may be left at the end
+        filtered = remove_thumbnail(&filtered);
+        let used_end = Tiff::parse(&filtered)
+            .and_then(|t| t.used_end(ifd0))
+            .map_or(filtered.len(), |end| prefix.saturating_add(end));
+        filtered.truncate(used_end.max(prefix + 8));
+    }
+
+    Some(filtered)
+}
+
+#[test]
+fn orientation_little_endian() {
+    let exif = [
+        b'E', b'x', b'i', b'f', 0, 0,
+        b'I', b'I', 0x2A, 0, 8, 0, 0, 0,
+        1, 0,
+        0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0,
+        0, 0, 0, 0,
+    ];
+    assert_eq!(orientation(&exif), Some(6))
+}
+
+#[test]
+fn orientation_big_endian() {
+    let exif = [
+        b'M', b'M', 0, 0x2A, 0, 0, 0, 8,
+        0, 1,
+        0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 8, 0, 0,
+        0, 0, 0, 0,
+    ];
+    assert_eq!(orientation(&exif), Some(8))
+}
+
+#[test]
+fn orientation_reset() {
+    let mut exif = [
+        b'M', b'M', 0, 0x2A, 0, 0, 0, 8,
+        0, 1,
+        0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0,
+        0, 0, 0, 0,
+    ];
+    assert_eq!(reset_orientation(&mut exif), Some(6));
+    assert_eq!(orientation(&exif), Some(1));
+}
+
+#[test]
+fn dimensions_update_drops_thumbnail() {
+    fn entry(exif: &mut Vec<u8>, tag: u16, value_type: u16, value: u32) {
+        exif.extend(tag.to_le_bytes());
+        exif.extend(value_type.to_le_bytes());
+        exif.extend(1u32.to_le_bytes());
+        exif.extend(value.to_le_bytes());
+    }
+
+    let mut exif = b"II*\0".to_vec();
+    exif.extend(8u32.to_le_bytes());
+    // IFD0 at 8, Exif IFD at 26, IFD1 at 56 and the thumbnail at 86
+    exif.extend(1u16.to_le_bytes());
+    entry(&mut exif, EXIF_IFD, LONG, 26);
+    exif.extend(56u32.to_le_bytes());
+    exif.extend(2u16.to_le_bytes());
+    entry(&mut exif, PIXEL_X_DIMENSION, SHORT, 15);
+    entry(&mut exif, PIXEL_Y_DIMENSION, SHORT, 7);
+    exif.extend(0u32.to_le_bytes());
+    exif.extend(2u16.to_le_bytes());
+    entry(&mut exif, THUMBNAIL_OFFSET, LONG, 86);
+    entry(&mut exif, THUMBNAIL_LENGTH, LONG, 4);
+    exif.extend(0u32.to_le_bytes());
+    exif.extend([0xFF, 0xD8, 0xFF, 0xD9]);
+
+    assert_eq!(update_dimensions(&exif, (15, 7), 15, 7), exif);
+
+    let updated = update_dimensions(&exif, (15, 7), 30, 70000);
+    let tiff = Tiff::parse(&updated).unwrap();
+    assert_eq!(tiff.find_entry(26, PIXEL_X_DIMENSION).and_then(|e| tiff.value(e)), Some(30));
+    assert_eq!(tiff.find_entry(26, PIXEL_Y_DIMENSION).and_then(|e| tiff.value(e)), Some(70000));
+    assert_eq!(tiff.u32(22), Some(0));
+    assert_eq!(updated.len(), 56);
+}
+
+#[test]
+fn geometry_change_drops_thumbnail_without_dimension_tags() {
+    let mut exif = b"II*\0".to_vec();
+    exif.extend(8u32.to_le_bytes());
+    // Empty IFD0 at 8, IFD1 at 14 and the thumbnail at 44
+    exif.extend(0u16.to_le_bytes());
+    exif.extend(14u32.to_le_bytes());
+    exif.extend(2u16.to_le_bytes());
+    for (tag, value) in [(THUMBNAIL_OFFSET, 44u32), (THUMBNAIL_LENGTH, 4)] {
+        exif.extend(tag.to_le_bytes());
+        exif.extend(LONG.to_le_bytes());
+        exif.extend(1u32.to_le_bytes());
+        exif.extend(value.to_le_bytes());
+    }
+    exif.extend(0u32.to_le_bytes());
+    exif.extend([0xFF, 0xD8, 0xFF, 0xD9]);
+
+    assert_eq!(update_dimensions(&exif, (40, 30), 40, 30), exif);
+    let updated = update_dimensions(&exif, (40, 30), 20, 15);
+    assert_eq!(Tiff::parse(&updated).unwrap().u32(10), Some(0));
+    assert_eq!(updated.len(), 14);
+}
+
+#[test]
+fn offsets_past_the_address_space_are_rejected() {
+    let tiff = Tiff::parse(b"II*\0\x08\0\0\0").unwrap();
+    assert_eq!(tiff.u16(usize::MAX), None);
+    assert_eq!(tiff.u32(usize::MAX - 1), None);
+    assert_eq!(tiff.next_ifd_link(usize::MAX), None);
+    assert_eq!(tiff.extent(usize::MAX - 2), None);
+}
+
+#[test]
+fn filter_strips_gps_and_serial_numbers() {
+    fn entry(exif: &mut Vec<u8>, tag: u16, value_type: u16, count: u32, value: u32) {
+        exif.extend(tag.to_le_bytes());
+        exif.extend(value_type.to_le_bytes());
+        exif.extend(count.to_le_bytes());
+        exif.extend(value.to_le_bytes());
+    }
+
+    let mut exif = b"II*\0".to_vec();
+    exif.extend(8u32.to_le_bytes());
+    // IFD0 at 8, Exif IFD at 50 with the serial number at 80, GPS IFD at 88 with the latitude at 118
+    exif.extend(3u16.to_le_bytes());
+    entry(&mut exif, ORIENTATION, SHORT, 1, 6);
+    entry(&mut exif, EXIF_IFD, LONG, 1, 50);
+    entry(&mut exif, GPS_IFD, LONG, 1, 88);
+    exif.extend(0u32.to_le_bytes());
+    exif.extend(2u16.to_le_bytes());
+    entry(&mut exif, MAKER_NOTE, 7, 4, 0x4B4E4F43);
+    entry(&mut exif, BODY_SERIAL_NUMBER, 2, 8, 80);
+    exif.extend(0u32.to_le_bytes());
+    exif.extend(b"SN123456");
+    exif.extend(2u16.to_le_bytes());
+    entry(&mut exif, 0x0001, 2, 2, u32::from(b'N'));
+    entry(&mut exif, 0x0002, 5, 3, 118);
+    exif.extend(0u32.to_le_bytes());
+    exif.extend([7u8; 24]);
+
+    let mut policy = MetadataPolicy::KEEP_ALL;
+    assert_eq!(filter(&exif, &policy), Some(exif.clone()));
+
+    policy.gps = false;
+    policy.serial_numbers = false;
+    let filtered = filter(&exif, &policy).unwrap();
+    let tiff = Tiff::parse(&filtered).unwrap();
+    assert_eq!(orientation(&filtered), Some(6));
+    assert_eq!(tiff.entries(8).len(), 2);
+    assert_eq!(tiff.sub_ifd(8, GPS_IFD), None);
+    assert_eq!(tiff.entries(50).len(), 1);
+    assert!(tiff.find_entry(50, MAKER_NOTE).is_some());
+    assert!(filtered[80..].iter().all(|b| *b == 0));
+
+    let filtered = filter(&exif, &MetadataPolicy { orientation: true, ..MetadataPolicy::STRIP_ALL }).unwrap();
+    assert_eq!(orientation(&filtered), Some(6));
+    assert_eq!(filtered.len(), 26);
+
+    assert_eq!(filter(&exif, &MetadataPolicy::STRIP_ALL), None);
+}
@@ -0,0 +1,260 @@
+//! Recognises common RGB display profiles by their colorants and tone curves, so that the large
+//! profiles some cameras and editors embed can be swapped for a compact equivalent.
+
+/// Largest difference in D50 colorant XYZ, or in linear tone curve output, still taken as a match.
+const COLORANT_TOLERANCE: f64 = 0.005;
+const CURVE_TOLERANCE: f64 = 0.002;
+
+/// Chromatic adaptation from D65 to the D50 PCS white, Bradford.
+const D65_TO_D50: [[f64; 3]; 3] = [
+    [1.047886, 0.022919, -0.050216],
+    [0.029582, 0.990484, -0.017079],
+    [-0.009252, 0.015073, 0.751678],
+];
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+/// IEC 61966-2-1 transfer function as an ICC parametric curve of type 3: g, a, b, c, d.
+const SRGB_CURVE: [f64; 5] = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+
+/// RGB profiles with a compact equivalent. Both use D65 white and the sRGB tone curve.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum KnownProfile {
+    Srgb,
+    DisplayP3,
+}
+
+impl KnownProfile {
+    const ALL: [KnownProfile; 2] = [KnownProfile::Srgb, KnownProfile::DisplayP3];
+
+    /// Red, green and blue colorants adapted to D50, as stored in rXYZ, gXYZ and bXYZ.
+    fn colorants(self) -> [[f64; 3]; 3] {
+        match self {
+            KnownProfile::Srgb => [
+                [0.436041, 0.222485, 0.013920],
+                [0.385113, 0.716905, 0.097067],
+                [0.143046, 0.060610, 0.713913],
+            ],
+            KnownProfile::DisplayP3 => [
+                [0.515119, 0.241189, -0.001050],
+                [0.291978, 0.692244, 0.041879],
+                [0.157103, 0.066567, 0.784071],
+            ],
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            KnownProfile::Srgb => "sRGB",
+            KnownProfile::DisplayP3 => "Display P3",
+        }
+    }
+
+    /// Minimal ICC v4 matrix/TRC display profile, a few hundred bytes.
+    pub fn profile(self) -> Vec<u8> {
+        let mut tags: Vec<([u8; 4], Vec<u8>)> = vec![
+            (*b"desc", mluc(self.description())),
+            (*b"cprt", mluc("No copyright, use freely")),
+            (*b"wtpt", xyz(D50)),
+            (*b"chad", sf32(D65_TO_D50)),
+        ];
+        for (signature, colorant) in [*b"rXYZ", *b"gXYZ", *b"bXYZ"].into_iter().zip(self.colorants()) {
+            tags.push((signature, xyz(colorant)));
+        }
+        let mut curve = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for parameter in SRGB_CURVE {
+            curve.extend(s15_fixed16(parameter));
+        }
+
+        // Header and tag table, the three tone curves share their data
+        let table_size = 4 + 12 * (tags.len() + 3);
+        let mut data = vec![];
+        let mut table = ((tags.len() + 3) as u32).to_be_bytes().to_vec();
+        let add_tag = |data: &mut Vec<u8>, table: &mut Vec<u8>, signatures: &[[u8; 4]], tag: &[u8]| {
+            for signature in signatures {
+                table.extend(signature);
+                table.extend(((128 + table_size + data.len()) as u32).to_be_bytes());
+                table.extend((tag.len() as u32).to_be_bytes());
+            }
+            data.extend(tag);
+            // Tag data starts on four byte boundaries
+            data.resize((data.len() + 3) & !3, 0);
+        };
+        for (signature, tag) in &tags {
+            add_tag(&mut data, &mut table, &[*signature], tag);
+        }
+        add_tag(&mut data, &mut table, &[*b"rTRC", *b"gTRC", *b"bTRC"], &curve);
+
+        let size = 128 + table.len() + data.len();
+        let mut profile = Vec::with_capacity(size);
+        profile.extend((size as u32).to_be_bytes());
+        profile.extend([0; 4]);
+        // Version 4.3
+        profile.extend([4, 0x30, 0, 0]);
+        profile.extend(b"mntrRGB XYZ ");
+        profile.extend([0x07, 0xE8, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        profile.extend(b"acsp");
+        profile.resize(68, 0);
+        profile.extend(D50.iter().flat_map(|v| s15_fixed16(*v)));
+        profile.resize(128, 0);
+        profile.extend(table);
+        profile.extend(data);
+        profile
+    }
+}
+
+/// The well-known profile `icc` is equivalent to, ignoring rendering intent tables.
+pub fn identify(icc: &[u8]) -> Option<KnownProfile> {
+    if icc.get(16..24) != Some(b"RGB XYZ ") {
+        return None;
+    }
+    let tags = tags(icc)?;
+    let tag = |signature: &[u8; 4]| tags.iter().find(|(s, _)| s == signature).map(|(_, data)| *data);
+
+    let mut colorants = [[0.0; 3]; 3];
+    for (colorant, signature) in colorants.iter_mut().zip([b"rXYZ", b"gXYZ", b"bXYZ"]) {
+        *colorant = read_xyz(tag(signature)?)?;
+    }
+    for signature in [b"rTRC", b"gTRC", b"bTRC"] {
+        let curve = evaluate_curve(tag(signature)?)?;
+        let matches = (0..=32).all(|i| {
+            let x = i as f64 / 32.0;
+            (curve(x) - parametric(&SRGB_CURVE, 3, x)).abs() <= CURVE_TOLERANCE
+        });
+        if !matches {
+            return None;
+        }
+    }
+
+    KnownProfile::ALL.into_iter().find(|known| {
+        known
+            .colorants()
+            .iter()
+            .flatten()
+            .zip(colorants.iter().flatten())
+            .all(|(a, b)| (a - b).abs() <= COLORANT_TOLERANCE)
+    })
+}
+
+/// Whether `icc` is equivalent to sRGB, pixels tagged with it need no conversion.
+pub fn is_srgb(icc: &[u8]) -> bool {
+    identify(icc) == Some(KnownProfile::Srgb)
+}
+
+/// Compact replacement for `icc` when it is a well-known profile and the replacement is smaller.
+pub fn compact(icc: &[u8]) -> Option<Vec<u8>> {
+    let compact = identify(icc)?.profile();
+    if compact.len() < icc.len() {
+        log::info!("Replacing {} byte ICC profile with a {} byte equivalent", icc.len(), compact.len());
+        Some(compact)
+    } else {
+        None
+    }
+}
+
+/// (signature, data) of each tag in the tag table.
+fn tags(icc: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let count = u32::from_be_bytes(icc.get(128..132)?.try_into().ok()?) as usize;
+    let mut tags = Vec::with_capacity(count.min(64));
+    for i in 0..count {
+        let entry = icc.get(132 + 12 * i..144 + 12 * i)?;
+        let offset = u32::from_be_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+        let size = u32::from_be_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+        tags.push(([entry[0], entry[1], entry[2], entry[3]], icc.get(offset..offset.checked_add(size)?)?));
+    }
+    Some(tags)
+}
+
+fn read_s15_fixed16(data: &[u8]) -> f64 {
+    i32::from_be_bytes([data[0], data[1], data[2], data[3]]) as f64 / 65536.0
+}
+
+fn read_xyz(tag: &[u8]) -> Option<[f64; 3]> {
+    if tag.len() < 20 || !tag.starts_with(b"XYZ ") {
+        return None;
+    }
+    Some([read_s15_fixed16(&tag[8..]), read_s15_fixed16(&tag[12..]), read_s15_fixed16(&tag[16..])])
+}
+
+/// The tone curve of a curv or para tag as a function on 0..=1.
+fn evaluate_curve(tag: &[u8]) -> Option<Box<dyn Fn(f64) -> f64 + '_>> {
+    if tag.starts_with(b"curv") {
+        let count = u32::from_be_bytes(tag.get(8..12)?.try_into().ok()?) as usize;
+        let entries = tag.get(12..12 + 2 * count)?;
+        let entry = move |i: usize| u16::from_be_bytes([entries[2 * i], entries[2 * i + 1]]) as f64;
+        return Some(match count {
+            0 => Box::new(|x| x),
+            // u8Fixed8 gamma
+            1 => Box::new(move |x: f64| x.powf(entry(0) / 256.0)),
+            _ => Box::new(move |x: f64| {
+                let position = x * (count - 1) as f64;
+                let i = (position as usize).min(count - 2);
+                let fraction = position - i as f64;
+                (entry(i) * (1.0 - fraction) + entry(i + 1) * fraction) / 65535.0
+            }),
+        });
+    }
+
+    if tag.starts_with(b"para") {
+        let function = u16::from_be_bytes(tag.get(8..10)?.try_into().ok()?);
+        let count = [1, 3, 4, 5, 7].get(function as usize)?;
+        let parameters: Vec<f64> = tag.get(12..12 + 4 * count)?.chunks(4).map(read_s15_fixed16).collect();
+        return Some(Box::new(move |x| parametric(&parameters, function, x)));
+    }
+
+    None
+}
+
+/// ICC parametric curve of the given function type, ICC.1:2010 table 68.
+fn parametric(parameters: &[f64], function: u16, x: f64) -> f64 {
+    let p = |i: usize| parameters.get(i).copied().unwrap_or_default();
+    let (g, a, b, c, d, e, f) = (p(0), p(1), p(2), p(3), p(4), p(5), p(6));
+    match function {
+        0 => x.powf(g),
+        1 if x >= -b / a => (a * x + b).powf(g),
+        1 => 0.0,
+        2 if x >= -b / a => (a * x + b).powf(g) + c,
+        2 => c,
+        3 if x >= d => (a * x + b).powf(g),
+        3 => c * x,
+        _ if x >= d => (a * x + b).powf(g) + e,
+        _ => c * x + f,
+    }
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz(value: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    tag.extend(value.iter().flat_map(|v| s15_fixed16(*v)));
+    tag
+}
+
+fn sf32(matrix: [[f64; 3]; 3]) -> Vec<u8> {
+    let mut tag = b"sf32\0\0\0\0".to_vec();
+    tag.extend(matrix.iter().flatten().flat_map(|v| s15_fixed16(*v)));
+    tag
+}
+
+/// Single en-US record multiLocalizedUnicodeType.
+fn mluc(text: &str) -> Vec<u8> {
+    let text: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+    let mut tag = b"mluc\0\0\0\0".to_vec();
+    tag.extend(1u32.to_be_bytes());
+    tag.extend(12u32.to_be_bytes());
+    tag.extend(b"enUS");
+    tag.extend((text.len() as u32).to_be_bytes());
+    tag.extend(28u32.to_be_bytes());
+    tag.extend(text);
+    tag
+}
+
+#[test]
+fn compact_profiles_identify_as_themselves() {
+    for known in KnownProfile::ALL {
+        let profile = known.profile();
+        assert_eq!(u32::from_be_bytes(profile[..4].try_into().unwrap()) as usize, profile.len());
+        assert!(profile.len() < 600);
+        assert_eq!(identify(&profile), Some(known));
+    }
+}
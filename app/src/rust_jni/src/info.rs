@@ -0,0 +1,346 @@
+use std::io;
+
+use lodepng::decode32;
+
+use crate::jpeg::ChromaSubsampling;
+use crate::utils::{get_filetype, SupportedFileTypes};
+use crate::{exif, jpeg, png, webp};
+
+const XMP_JPEG_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ColorSpace {
+    Grayscale,
+    Rgb,
+    YCbCr,
+    Cmyk,
+    Ycck,
+    Indexed,
+    Unknown,
+}
+
+impl ColorSpace {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorSpace::Grayscale => "Grayscale",
+            ColorSpace::Rgb => "RGB",
+            ColorSpace::YCbCr => "YCbCr",
+            ColorSpace::Cmyk => "CMYK",
+            ColorSpace::Ycck => "YCCK",
+            ColorSpace::Indexed => "Indexed",
+            ColorSpace::Unknown => "Unknown",
+        }
+    }
+}
+
+/// What the file says about itself, read from its headers without a full decode
+/// unless alpha usage has to be checked.
+pub struct ImageInfo {
+    pub file_type: SupportedFileTypes,
+    pub width: u32,
+    pub height: u32,
+    pub orientation: u16,
+    pub bit_depth: u8,
+    pub color_space: ColorSpace,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub has_alpha: bool,
+    pub uses_alpha: bool,
+    pub jpeg_quality: Option<u32>,
+    pub frame_count: u32,
+    pub icc_size: usize,
+    pub exif_size: usize,
+    pub xmp_size: usize,
+    pub other_metadata_size: usize,
+}
+
+impl ImageInfo {
+    fn new(file_type: SupportedFileTypes) -> ImageInfo {
+        ImageInfo {
+            file_type,
+            width: 0,
+            height: 0,
+            orientation: 1,
+            bit_depth: 8,
+            color_space: ColorSpace::Unknown,
+            chroma_subsampling: None,
+            has_alpha: false,
+            uses_alpha: false,
+            jpeg_quality: None,
+            frame_count: 1,
+            icc_size: 0,
+            exif_size: 0,
+            xmp_size: 0,
+            other_metadata_size: 0,
+        }
+    }
+}
+
+pub fn analyze(in_file: &[u8]) -> Result<ImageInfo, io::Error> {
+    match get_filetype(in_file.to_vec()) {
+        SupportedFileTypes::Jpeg => analyze_jpeg(in_file),
+        SupportedFileTypes::Png => analyze_png(in_file),
+        SupportedFileTypes::WebP => analyze_webp(in_file),
+        SupportedFileTypes::Unkn => Err(io::Error::new(io::ErrorKind::Other, "Unknown file type")),
+    }
+}
+
+pub fn mime_type(file_type: SupportedFileTypes) -> &'static str {
+    match file_type {
+        SupportedFileTypes::Jpeg => "image/jpeg",
+        SupportedFileTypes::Png => "image/png",
+        SupportedFileTypes::WebP => "image/webp",
+        SupportedFileTypes::Unkn => "application/octet-stream",
+    }
+}
+
+pub fn subsampling_name(subsampling: ChromaSubsampling) -> &'static str {
+    match subsampling {
+        ChromaSubsampling::CS444 => "4:4:4",
+        ChromaSubsampling::CS422 => "4:2:2",
+        ChromaSubsampling::CS420 => "4:2:0",
+        ChromaSubsampling::CS411 => "4:1:1",
+        ChromaSubsampling::Auto => "Auto",
+    }
+}
+
+fn analyze_jpeg(in_file: &[u8]) -> Result<ImageInfo, io::Error> {
+    let mut info = ImageInfo::new(SupportedFileTypes::Jpeg);
+    let mut exif = None;
+    let mut saw_jfif = false;
+    let mut adobe_transform = None;
+    // (id, horizontal sampling, vertical sampling)
+    let mut components: Vec<(u8, u8, u8)> = vec![];
+
+    for (marker, payload) in jpeg::segments(in_file) {
+        match marker {
+            // SOFn, DHT/JPG/DAC share the range
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                if payload.len() < 6 {
+                    continue;
+                }
+                info.bit_depth = payload[0];
+                info.height = u16::from_be_bytes([payload[1], payload[2]]) as u32;
+                info.width = u16::from_be_bytes([payload[3], payload[4]]) as u32;
+                components = payload[6..]
+                    .chunks_exact(3)
+                    .take(payload[5] as usize)
+                    .map(|c| (c[0], c[1] >> 4, c[1] & 0x0F))
+                    .collect();
+            }
+            0xE0 if payload.starts_with(b"JFIF\0") => saw_jfif = true,
+            0xE1 if payload.starts_with(b"Exif\0\0") => {
+                info.exif_size += payload.len();
+                exif = Some(payload);
+            }
+            0xE1 if payload.starts_with(XMP_JPEG_NAMESPACE) => info.xmp_size += payload.len(),
+            // Sequence number and chunk count follow the identifier
+            0xE2 if payload.starts_with(b"ICC_PROFILE\0") => info.icc_size += payload.len().saturating_sub(14),
+            0xEE if payload.starts_with(b"Adobe") && payload.len() >= 12 => adobe_transform = Some(payload[11]),
+            0xE0..=0xEF | 0xFE => info.other_metadata_size += payload.len(),
+            _ => {}
+        }
+    }
+
+    if components.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, "Missing JPEG frame header"));
+    }
+
+    // Same rules libjpeg uses to guess jpeg_color_space
+    info.color_space = match components.len() {
+        1 => ColorSpace::Grayscale,
+        3 => {
+            let ids: Vec<u8> = components.iter().map(|c| c.0).collect();
+            if saw_jfif {
+                ColorSpace::YCbCr
+            } else if let Some(transform) = adobe_transform {
+                if transform == 0 { ColorSpace::Rgb } else { ColorSpace::YCbCr }
+            } else if ids == b"RGB" {
+                ColorSpace::Rgb
+            } else {
+                ColorSpace::YCbCr
+            }
+        }
+        4 => match adobe_transform {
+            None | Some(0) => ColorSpace::Cmyk,
+            Some(_) => ColorSpace::Ycck,
+        },
+        _ => ColorSpace::Unknown,
+    };
+
+    if matches!(info.color_space, ColorSpace::YCbCr | ColorSpace::Ycck) {
+        info.chroma_subsampling = jpeg_subsampling(&components);
+    }
+    info.jpeg_quality = jpeg::estimate_quality(in_file, jpeg::ANNEX_K_TABLES).ok();
+    info.orientation = exif.and_then(exif::orientation).unwrap_or(1);
+
+    Ok(info)
+}
+
+fn jpeg_subsampling(components: &[(u8, u8, u8)]) -> Option<ChromaSubsampling> {
+    let (_, luma_h, luma_v) = components[0];
+    let (_, chroma_h, chroma_v) = components[1];
+    if components[1..3].iter().any(|c| c.1 != chroma_h || c.2 != chroma_v)
+        || chroma_h == 0
+        || chroma_v == 0
+        || luma_h % chroma_h != 0
+        || luma_v % chroma_v != 0
+    {
+        return None;
+    }
+
+    match (luma_h / chroma_h, luma_v / chroma_v) {
+        (1, 1) => Some(ChromaSubsampling::CS444),
+        (2, 1) => Some(ChromaSubsampling::CS422),
+        (2, 2) => Some(ChromaSubsampling::CS420),
+        (4, 1) => Some(ChromaSubsampling::CS411),
+        _ => None,
+    }
+}
+
+fn analyze_png(in_file: &[u8]) -> Result<ImageInfo, io::Error> {
+    let mut info = ImageInfo::new(SupportedFileTypes::Png);
+    let mut exif = None;
+    let mut color_type = None;
+    let mut has_trns = false;
+
+    for (chunk_type, data) in png::chunks(in_file) {
+        match &chunk_type {
+            b"IHDR" if data.len() >= 13 => {
+                info.width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                info.height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+                info.bit_depth = data[8];
+                color_type = Some(data[9]);
+            }
+            b"tRNS" => has_trns = true,
+            b"acTL" if data.len() >= 4 => {
+                info.frame_count = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+            }
+            b"iCCP" => info.icc_size += data.len(),
+            b"eXIf" => {
+                info.exif_size += data.len();
+                exif = Some(data);
+            }
+            b"iTXt" if data.starts_with(XMP_PNG_KEYWORD) => info.xmp_size += data.len(),
+            b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => info.other_metadata_size += data.len(),
+            _ => {}
+        }
+    }
+
+    let color_type = match color_type {
+        Some(c) => c,
+        None => return Err(io::Error::new(io::ErrorKind::Other, "Missing PNG header")),
+    };
+
+    info.color_space = match color_type {
+        0 | 4 => ColorSpace::Grayscale,
+        2 | 6 => ColorSpace::Rgb,
+        3 => ColorSpace::Indexed,
+        _ => ColorSpace::Unknown,
+    };
+    info.has_alpha = color_type == 4 || color_type == 6 || has_trns;
+    if info.has_alpha {
+        info.uses_alpha = match decode32(in_file) {
+            Ok(bitmap) => bitmap.buffer.iter().any(|p| p.a < 255),
+            Err(_) => true,
+        };
+    }
+    info.orientation = exif.and_then(exif::orientation).unwrap_or(1);
+
+    Ok(info)
+}
+
+fn analyze_webp(in_file: &[u8]) -> Result<ImageInfo, io::Error> {
+    let mut info = ImageInfo::new(SupportedFileTypes::WebP);
+    let mut exif = None;
+    let mut canvas_size = None;
+    let mut animation_frames = 0;
+    let mut bitstream = None;
+
+    for (fourcc, data) in webp::chunks(in_file) {
+        match &fourcc {
+            b"VP8X" if data.len() >= 10 => {
+                info.has_alpha |= data[0] & 0x10 != 0;
+                let width = u32::from_le_bytes([data[4], data[5], data[6], 0]) + 1;
+                let height = u32::from_le_bytes([data[7], data[8], data[9], 0]) + 1;
+                canvas_size = Some((width, height));
+            }
+            b"ALPH" => info.has_alpha = true,
+            b"VP8 " | b"VP8L" => bitstream = bitstream.or(Some((fourcc, data))),
+            b"ANMF" => {
+                animation_frames += 1;
+                // 16 byte frame header, then the frame's own ALPH/VP8/VP8L chunks
+                if bitstream.is_none() && data.len() > 16 {
+                    bitstream = webp::sub_chunks(&data[16..])
+                        .into_iter()
+                        .find(|(f, _)| f == b"VP8 " || f == b"VP8L");
+                }
+            }
+            b"ICCP" => info.icc_size += data.len(),
+            b"EXIF" => {
+                info.exif_size += data.len();
+                exif = Some(data);
+            }
+            b"XMP " => info.xmp_size += data.len(),
+            _ => {}
+        }
+    }
+
+    let (width, height) = match bitstream {
+        Some((fourcc, data)) if &fourcc == b"VP8 " && data.len() >= 10 => {
+            info.color_space = ColorSpace::YCbCr;
+            info.chroma_subsampling = Some(ChromaSubsampling::CS420);
+            (
+                (u16::from_le_bytes([data[6], data[7]]) & 0x3FFF) as u32,
+                (u16::from_le_bytes([data[8], data[9]]) & 0x3FFF) as u32,
+            )
+        }
+        Some((_, data)) if data.len() >= 5 => {
+            info.color_space = ColorSpace::Rgb;
+            let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            info.has_alpha |= (bits >> 28) & 1 != 0;
+            ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1)
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::Other, "Missing WebP bitstream")),
+    };
+    (info.width, info.height) = canvas_size.unwrap_or((width, height));
+    info.frame_count = animation_frames.max(1);
+
+    if info.has_alpha {
+        info.uses_alpha = if animation_frames > 0 {
+            true
+        } else {
+            match webp::decode(in_file) {
+                Ok(image) => image.as_rgba8().is_some_and(|i| i.pixels().any(|p| p[3] < 255)),
+                Err(_) => true,
+            }
+        };
+    }
+    info.orientation = exif.and_then(exif::orientation).unwrap_or(1);
+
+    Ok(info)
+}
+
+#[test]
+fn subsampling_from_sampling_factors() {
+    let cs420 = [(1, 2, 2), (2, 1, 1), (3, 1, 1)];
+    let cs422 = [(1, 2, 1), (2, 1, 1), (3, 1, 1)];
+    let cs444 = [(1, 1, 1), (2, 1, 1), (3, 1, 1)];
+    let cs440 = [(1, 1, 2), (2, 1, 1), (3, 1, 1)];
+
+    assert!(jpeg_subsampling(&cs420) == Some(ChromaSubsampling::CS420));
+    assert!(jpeg_subsampling(&cs422) == Some(ChromaSubsampling::CS422));
+    assert!(jpeg_subsampling(&cs444) == Some(ChromaSubsampling::CS444));
+    assert!(jpeg_subsampling(&cs440).is_none());
+}
+
+#[test]
+fn jpeg_quality_on_the_libjpeg_scale() {
+    // The image encoder scales the Annex K tables like libjpeg's jpeg_set_quality
+    let image = image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([(x * 8) as u8, (y * 8) as u8, 128]));
+    for quality in [75, 90] {
+        let mut jpeg = vec![];
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, quality).encode_image(&image).unwrap();
+        assert_eq!(analyze(&jpeg).unwrap().jpeg_quality, Some(quality as u32));
+    }
+}
@@ -2,16 +2,34 @@ use std::ptr;
 use std::{io, mem};
 use std::panic::catch_unwind;
 
-use image::ImageOutputFormat::Jpeg;
+use image::DynamicImage;
 use img_parts::{DynImage, ImageEXIF, ImageICC};
 use libc::free;
 use mozjpeg_sys::*;
 
-use crate::CSParameters;
-use crate::resize::resize;
+use crate::{color, exif, icc, CSParameters};
+use crate::color::Layout;
+use crate::orientation::Transform;
+use crate::transupp::{
+    jpeg_transform_info, jtransform_adjust_parameters, jtransform_execute_transform, jtransform_request_workspace,
+    JXFORM_FLIP_H, JXFORM_FLIP_V, JXFORM_NONE, JXFORM_ROT_180, JXFORM_ROT_270, JXFORM_ROT_90, JXFORM_TRANSPOSE,
+    JXFORM_TRANSVERSE, JCROP_POS,
+};
+use crate::resize::{self, resize_samples};
+use crate::similarity::Reference;
+use crate::utils::{search_lowest_quality, search_quality};
 
 static mut JPEG_ERROR: c_int = 0;
 
+/// Largest per-pixel Cb/Cr deviation from neutral, in sample levels, that still counts as grayscale.
+const NEUTRAL_CHROMA_TOLERANCE: i32 = 2;
+
+/// Base quantization tables of the JPEG specification, Annex K, on which libjpeg qualities are given.
+pub const ANNEX_K_TABLES: u32 = 0;
+
+/// Identifier of the APP2 segments an ICC profile is split across.
+const ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum ChromaSubsampling {
     CS444,
@@ -21,6 +39,33 @@ pub enum ChromaSubsampling {
     Auto
 }
 
+/// Whether colour JPEGs are reduced to their luma component.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Grayscale {
+    Off,
+    On,
+    /// Only when the chroma components carry no visible colour
+    Auto,
+}
+
+/// Rectangle in pixels of the (transformed) output image.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// mozjpeg compression profile, picks the encoder defaults before any tuning is applied.
+#[derive(Copy, Clone, PartialEq)]
+pub enum CompressionProfile {
+    /// All mozjpeg extensions available
+    MaxCompression,
+    /// libjpeg-turbo defaults, trellis and deringing settings are ignored
+    Fastest,
+}
+
 pub fn compress(
     in_file: Vec<u8>,
     out_buffer: &mut Vec<u8>,
@@ -30,38 +75,537 @@ pub fn compress(
     Ok(())
 }
 
+/// Losslessly crops to the iMCU aligned rectangle enclosing `parameters.jpeg.crop`, applying the
+/// orientation and transform on the way like `jpegtran -crop`. Returns the new file with the rectangle
+/// that was actually kept and clears from `parameters` what has been done.
+pub fn crop(mut in_file: Vec<u8>, parameters: &mut CSParameters) -> Result<(Vec<u8>, Option<Crop>), io::Error> {
+    orient(&mut in_file, parameters);
+    // Intermediate file only, the scan layout is picked by the final pass
+    let mut crop_parameters = *parameters;
+    crop_parameters.jpeg.progressive = false;
+
+    parameters.jpeg.transform = Transform::None;
+    parameters.jpeg.crop = None;
+
+    unsafe {
+        match catch_unwind(|| lossless(in_file, &crop_parameters)) {
+            Ok(cb) => cb,
+            Err(_) => {
+                let error_code = JPEG_ERROR;
+                Err(io::Error::new(io::ErrorKind::Other, format!("Internal JPEG error: {}", error_code)))
+            }
+        }
+    }
+}
+
 pub fn compress_to_memory(mut in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::Error>
 {
-    if parameters.width > 0 || parameters.height > 0 {
-        if parameters.keep_metadata {
-            let metadata = extract_metadata(in_file.clone());
-            in_file = resize(in_file, parameters.width, parameters.height,
-                             Jpeg(parameters.jpeg.quality as u8))?;
-            in_file = save_metadata(in_file, metadata.0, metadata.1);
-        } else {
-            in_file = resize(in_file, parameters.width, parameters.height,
-                             Jpeg(parameters.jpeg.quality as u8))?;
-        }
+    let mut parameters = *parameters;
+    orient(&mut in_file, &mut parameters);
+    if parameters.metadata.icc {
+        in_file = compact_icc(in_file);
+    }
+    // The requested size is the one of the transformed output, but resizing comes first
+    if parameters.jpeg.transform.swaps_axes() {
+        mem::swap(&mut parameters.width, &mut parameters.height);
     }
+    let parameters = &parameters;
+
+    let cmyk = is_cmyk(&in_file);
+    let must_resize = resize::dimensions(&in_file).is_some_and(|(w, h)| resize::is_needed(w, h, parameters));
 
     unsafe {
         return match catch_unwind(|| {
-            if parameters.optimize {
-                lossless(in_file, parameters)
+            let mut parameters = *parameters;
+            if parameters.jpeg.grayscale == Grayscale::Auto {
+                parameters.jpeg.grayscale = if has_neutral_chroma(&in_file) {
+                    log::info!("Chroma is neutral, dropping it");
+                    Grayscale::On
+                } else {
+                    Grayscale::Off
+                };
+            }
+            let parameters = &parameters;
+            let cmyk_to_rgb = cmyk && parameters.jpeg.cmyk_to_rgb;
+            let icc = if parameters.convert_to_srgb || cmyk_to_rgb { icc_profile(&in_file) } else { None };
+            let convert = parameters.convert_to_srgb && icc.as_deref().is_some_and(|icc| !icc::is_srgb(icc));
+
+            if parameters.optimize && parameters.output_size == 0 && parameters.max_dssim == 0.0
+                && !must_resize && !cmyk_to_rgb && !convert
+            {
+                lossless(in_file, parameters).map(|(compressed, _)| compressed)
             } else {
-                lossy(in_file, parameters)
+                let transform = parameters.jpeg.transform;
+                let grayscale = parameters.jpeg.grayscale == Grayscale::On;
+                // Decoded once, resized, transformed and converted as samples, then encoded
+                let to_rgb = convert || must_resize || cmyk_to_rgb;
+                let mut samples = decode(&in_file, parameters.metadata.keeps_any(), grayscale, to_rgb);
+                if must_resize {
+                    samples = samples.resized(parameters)?;
+                }
+                let mut samples = samples.transformed(transform);
+                // An sRGB profile is left alone, it describes the samples as they are
+                let converted = (convert || cmyk_to_rgb) && icc.is_some_and(|icc| samples.convert_to_srgb(&icc));
+                if cmyk_to_rgb && !converted {
+                    samples.cmyk_to_rgb();
+                }
+
+                // Grayscale output is scored against the grayscale samples, the colour source could never match
+                if converted || must_resize || cmyk_to_rgb || grayscale {
+                    encode(&samples, || Reference::from_image(&samples.image()), parameters)
+                } else {
+                    encode(&samples, || Reference::from_file(&in_file, transform), parameters)
+                }
             }
         }) {
             Ok(cb) => cb,
-            Err(_) => Err(io::Error::new(io::ErrorKind::Other, format!("Internal JPEG error: {}", JPEG_ERROR)))
+            Err(_) => {
+                let error_code = JPEG_ERROR;
+                Err(io::Error::new(io::ErrorKind::Other, format!("Internal JPEG error: {}", error_code)))
+            }
+        };
+    }
+}
+
+/// Encodes already decoded pixels, e.g. when converting from another format.
+/// Transparent pixels are composited onto `parameters.matte_color`.
+pub fn compress_image(image: &DynamicImage, parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    let flattened;
+    let image = if image.color().has_alpha() {
+        flattened = flatten(image, parameters.matte_color);
+        &flattened
+    } else {
+        image
+    };
+
+    let samples = if image.color().has_color() {
+        Samples {
+            buffer: image.to_rgb8().into_raw(),
+            width: image.width(),
+            height: image.height(),
+            color_space: JCS_RGB,
+            inverted: false,
+            markers: vec![],
+            source_size: (image.width(), image.height()),
+        }
+    } else {
+        Samples {
+            buffer: image.to_luma8().into_raw(),
+            width: image.width(),
+            height: image.height(),
+            color_space: JCS_GRAYSCALE,
+            inverted: false,
+            markers: vec![],
+            source_size: (image.width(), image.height()),
+        }
+    };
+
+    unsafe {
+        match catch_unwind(|| encode(&samples, || Reference::from_image(image), parameters)) {
+            Ok(cb) => cb,
+            Err(_) => {
+                let error_code = JPEG_ERROR;
+                Err(io::Error::new(io::ErrorKind::Other, format!("Internal JPEG error: {}", error_code)))
+            }
+        }
+    }
+}
+
+/// Composites straight alpha pixels over an opaque matte: premultiply, then add the matte
+/// weighted by the remaining coverage.
+fn flatten(image: &DynamicImage, matte_color: [u8; 3]) -> DynamicImage {
+    let mut rgba = image.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for c in 0..3 {
+            let premultiplied = pixel[c] as u32 * alpha;
+            pixel[c] = ((premultiplied + matte_color[c] as u32 * (255 - alpha) + 127) / 255) as u8;
+        }
+        pixel[3] = 255;
+    }
+
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+}
+
+/// Decoded samples, kept in the colour space they were stored in so that
+/// re-encoding does not add a colour conversion round trip.
+struct Samples {
+    buffer: Vec<u8>,
+    width: u32,
+    height: u32,
+    color_space: J_COLOR_SPACE,
+    /// CMYK written by Adobe applications, stored with 0 meaning full ink
+    inverted: bool,
+    markers: Vec<(c_int, Vec<u8>)>,
+    /// Size of the image `markers` were read from
+    source_size: (u32, u32),
+}
+
+impl Samples {
+    fn transformed(self, transform: Transform) -> Samples {
+        if transform == Transform::None {
+            return self;
+        }
+
+        let channels = self.buffer.len() / (self.width as usize * self.height as usize);
+        let (buffer, width, height) = transform.apply_samples(&self.buffer, self.width, self.height, channels);
+        Samples { buffer, width, height, ..self }
+    }
+
+    /// Resizes grayscale, RGB or CMYK samples. Padding is the matte colour, or blank paper for CMYK.
+    fn resized(self, parameters: &CSParameters) -> Result<Samples, io::Error> {
+        let [r, g, b] = parameters.matte_color;
+        let background = match self.color_space {
+            JCS_CMYK if self.inverted => vec![255; 4],
+            JCS_CMYK => vec![0; 4],
+            // BT.601 luma, as JPEG uses
+            JCS_GRAYSCALE => vec![((299 * r as u32 + 587 * g as u32 + 114 * b as u32 + 500) / 1000) as u8],
+            _ => vec![r, g, b],
         };
+        let (buffer, width, height) = resize_samples(self.buffer, self.width, self.height, parameters, &background)?;
+        Ok(Samples { buffer, width, height, ..self })
+    }
+
+    /// Converts RGB or CMYK samples tagged with the `icc` profile to sRGB and drops the profile.
+    /// Returns false and leaves everything as is if the profile cannot be used.
+    fn convert_to_srgb(&mut self, icc: &[u8]) -> bool {
+        match self.color_space {
+            JCS_RGB => {
+                if !color::to_srgb(icc, &mut self.buffer, Layout::Rgb) {
+                    return false;
+                }
+            }
+            JCS_CMYK => {
+                let converted = if self.inverted {
+                    let cmyk: Vec<u8> = self.buffer.iter().map(|v| 255 - v).collect();
+                    color::cmyk_to_srgb(icc, &cmyk)
+                } else {
+                    color::cmyk_to_srgb(icc, &self.buffer)
+                };
+                match converted {
+                    Some(rgb) => self.buffer = rgb,
+                    None => return false,
+                }
+            }
+            _ => return false,
+        }
+
+        self.mark_rgb();
+        true
+    }
+
+    /// Converts CMYK samples to RGB without a colour profile, for files that lack a usable one.
+    fn cmyk_to_rgb(&mut self) {
+        if self.color_space == JCS_CMYK {
+            self.buffer = cmyk_to_rgb(&self.buffer, self.inverted);
+            self.mark_rgb();
+        }
+    }
+
+    /// Marks converted samples as RGB. Any embedded profile described the old samples and is dropped.
+    fn mark_rgb(&mut self) {
+        self.color_space = JCS_RGB;
+        self.inverted = false;
+        self.markers.retain(|(marker, data)| !(*marker == 0xE2 && data.starts_with(ICC_MARKER)));
+    }
+
+    /// The samples as an image, CMYK is converted without its colour profile.
+    fn image(&self) -> DynamicImage {
+        let (width, height) = (self.width, self.height);
+        match self.color_space {
+            JCS_GRAYSCALE => {
+                DynamicImage::ImageLuma8(image::GrayImage::from_raw(width, height, self.buffer.clone()).unwrap())
+            }
+            JCS_CMYK => {
+                let rgb = cmyk_to_rgb(&self.buffer, self.inverted);
+                DynamicImage::ImageRgb8(image::RgbImage::from_raw(width, height, rgb).unwrap())
+            }
+            _ => DynamicImage::ImageRgb8(image::RgbImage::from_raw(width, height, self.buffer.clone()).unwrap()),
+        }
+    }
+}
+
+unsafe fn encode<F>(samples: &Samples, reference: F, parameters: &CSParameters) -> Result<Vec<u8>, io::Error>
+where
+    F: FnOnce() -> Result<Reference, io::Error>,
+{
+    if parameters.output_size > 0 {
+        compress_to_size(samples, parameters)
+    } else if parameters.max_dssim > 0.0 {
+        compress_to_dssim(samples, &reference()?, parameters)
+    } else {
+        lossy(samples, parameters)
+    }
+}
+
+/// Estimates the quality setting the file was saved at by matching its quantization
+/// tables against the ones mozjpeg generates for each quality from the `quant_table`
+/// base tables. [`ANNEX_K_TABLES`] gives the usual libjpeg quality, other base tables
+/// the quality the encoder would need with them for the same quantization.
+pub fn estimate_quality(in_file: &[u8], quant_table: u32) -> Result<u32, io::Error> {
+    unsafe {
+        match catch_unwind(|| read_quality(in_file, quant_table)) {
+            Ok(q) => q,
+            Err(_) => {
+                let error_code = JPEG_ERROR;
+                Err(io::Error::new(io::ErrorKind::Other, format!("Internal JPEG error: {}", error_code)))
+            }
+        }
+    }
+}
+
+unsafe fn read_quality(in_file: &[u8], quant_table: u32) -> Result<u32, io::Error> {
+    let mut src_info: jpeg_decompress_struct = mem::zeroed();
+    let mut src_err = mem::zeroed();
+
+    src_info.common.err = jpeg_std_error(&mut src_err);
+    (*src_info.common.err).error_exit = Some(error_handler);
+    (*src_info.common.err).output_message = Some(error_message_handler);
+
+    jpeg_create_decompress(&mut src_info);
+    jpeg_mem_src(&mut src_info, in_file.as_ptr(), in_file.len() as _);
+    jpeg_read_header(&mut src_info, true as boolean);
+
+    // Luma table first, then the table of the first chroma component if there is one
+    let mut source_tables = vec![];
+    let tables_components = if src_info.num_components >= 3 { 2 } else { 1 };
+    for c in 0..tables_components {
+        let table_no = (*src_info.comp_info.add(c)).quant_tbl_no as usize;
+        let table = src_info.quant_tbl_ptrs[table_no];
+        if !table.is_null() {
+            source_tables.push((c, (*table).quantval));
+        }
+    }
+    jpeg_destroy_decompress(&mut src_info);
+
+    if source_tables.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other, "No quantization tables found"));
+    }
+
+    let mut dst_info: jpeg_compress_struct = mem::zeroed();
+    let mut dst_err = mem::zeroed();
+
+    dst_info.common.err = jpeg_std_error(&mut dst_err);
+    (*dst_info.common.err).error_exit = Some(error_handler);
+    (*dst_info.common.err).output_message = Some(error_message_handler);
+
+    jpeg_create_compress(&mut dst_info);
+    dst_info.in_color_space = JCS_RGB;
+    dst_info.input_components = 3;
+    jpeg_set_defaults(&mut dst_info);
+    jpeg_c_set_int_param(&mut dst_info, J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX, quant_table as c_int);
+
+    // (distance, quality); ties keep the lowest quality so the estimate errs on the safe side
+    let mut best = (u64::MAX, 100);
+    for quality in 1..=100 {
+        jpeg_set_quality(&mut dst_info, quality, false as boolean);
+        let distance: u64 = source_tables
+            .iter()
+            .map(|(t, source)| {
+                let reference = &(*dst_info.quant_tbl_ptrs[*t]).quantval;
+                source
+                    .iter()
+                    .zip(reference.iter())
+                    .map(|(a, b)| (*a as i64 - *b as i64).unsigned_abs())
+                    .sum::<u64>()
+            })
+            .sum();
+        if distance < best.0 {
+            best = (distance, quality as u32);
+        }
+    }
+    jpeg_destroy_compress(&mut dst_info);
+
+    Ok(best.1)
+}
+
+/// Moves the EXIF orientation into `parameters.jpeg.transform` when auto-orienting.
+fn orient(in_file: &mut [u8], parameters: &mut CSParameters) {
+    if parameters.auto_orient {
+        if let Some(orientation) = reset_orientation(in_file) {
+            parameters.jpeg.transform = Transform::from_orientation(orientation).then(parameters.jpeg.transform);
+        }
+        parameters.auto_orient = false;
+    }
+}
+
+/// Resets the orientation in the EXIF segment to 1, returning the one it had.
+fn reset_orientation(in_file: &mut [u8]) -> Option<u16> {
+    let range = segments(in_file)
+        .into_iter()
+        .find(|(marker, data)| *marker == 0xE1 && data.starts_with(b"Exif\0\0"))
+        .map(|(_, data)| {
+            let start = data.as_ptr() as usize - in_file.as_ptr() as usize;
+            start..start + data.len()
+        })?;
+
+    exif::reset_orientation(&mut in_file[range])
+}
+
+/// Header segments as (marker, payload) pairs, up to and including the first SOS.
+pub fn segments(in_file: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = vec![];
+    if !in_file.starts_with(&[0xFF, 0xD8]) {
+        return segments;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= in_file.len() && in_file[pos] == 0xFF {
+        let marker = in_file[pos + 1];
+        match marker {
+            // Fill byte
+            0xFF => pos += 1,
+            // Standalone markers have no length
+            0x01 | 0xD0..=0xD8 => pos += 2,
+            0xD9 => break,
+            _ => {
+                let length = u16::from_be_bytes([in_file[pos + 2], in_file[pos + 3]]) as usize;
+                if length < 2 || pos + 2 + length > in_file.len() {
+                    break;
+                }
+                segments.push((marker, &in_file[pos + 4..pos + 2 + length]));
+                // Entropy coded data follows
+                if marker == 0xDA {
+                    break;
+                }
+                pos += 2 + length;
+            }
+        }
+    }
+
+    segments
+}
+
+/// Whether the frame has four components, i.e. it is stored as CMYK or YCCK.
+pub fn is_cmyk(in_file: &[u8]) -> bool {
+    segments(in_file).into_iter().any(|(marker, data)| {
+        // Start of frame markers, DHT, JPG and DAC share the range
+        matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) && data.get(5) == Some(&4)
+    })
+}
+
+/// Whether `grayscale` reduces `in_file` to its luma when it is decoded to pixels, which CMYK never is.
+pub fn drops_chroma(in_file: &[u8], grayscale: Grayscale) -> bool {
+    match grayscale {
+        Grayscale::Off => false,
+        Grayscale::On => !is_cmyk(in_file),
+        Grayscale::Auto => unsafe { catch_unwind(|| has_neutral_chroma(in_file)).unwrap_or(false) },
+    }
+}
+
+/// Decodes a CMYK or YCCK file to RGB, through its ICC profile when it has a usable one.
+pub fn decode_cmyk(in_file: &[u8]) -> Result<DynamicImage, io::Error> {
+    unsafe {
+        match catch_unwind(|| {
+            let mut samples = decode(in_file, false, false, true);
+            if !icc_profile(in_file).is_some_and(|icc| samples.convert_to_srgb(&icc)) {
+                samples.cmyk_to_rgb();
+            }
+            samples.image()
+        }) {
+            Ok(image) => Ok(image),
+            Err(_) => {
+                let error_code = JPEG_ERROR;
+                Err(io::Error::new(io::ErrorKind::Other, format!("Internal JPEG error: {}", error_code)))
+            }
+        }
+    }
+}
+
+/// Naive CMYK to RGB: each colour channel is what its ink and the black ink leave of the paper white.
+fn cmyk_to_rgb(cmyk: &[u8], inverted: bool) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(cmyk.len() / 4 * 3);
+    for pixel in cmyk.chunks_exact(4) {
+        // Inverted samples already hold 255 - ink
+        let paper = |v: u8| if inverted { v as u32 } else { 255 - v as u32 };
+        let k = paper(pixel[3]);
+        rgb.extend((0..3).map(|c| ((paper(pixel[c]) * k + 127) / 255) as u8));
     }
+    rgb
+}
+
+/// The embedded ICC profile, reassembled from its APP2 chunks in sequence order.
+fn icc_profile(in_file: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<(u8, &[u8])> = segments(in_file)
+        .into_iter()
+        .filter(|(marker, data)| *marker == 0xE2 && data.len() > 14 && data.starts_with(ICC_MARKER))
+        .map(|(_, data)| (data[12], &data[14..]))
+        .collect();
+    if chunks.is_empty() {
+        return None;
+    }
+
+    chunks.sort_by_key(|(sequence, _)| *sequence);
+    Some(chunks.into_iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect())
+}
+
+/// Swaps a well-known ICC profile for its compact equivalent.
+fn compact_icc(in_file: Vec<u8>) -> Vec<u8> {
+    let compact = match icc_profile(&in_file).and_then(|profile| icc::compact(&profile)) {
+        Some(c) => c,
+        None => return in_file,
+    };
+    let mut jpeg = match img_parts::jpeg::Jpeg::from_bytes(in_file.clone().into()) {
+        Ok(j) => j,
+        Err(_) => return in_file,
+    };
+    jpeg.set_icc_profile(Some(compact.into()));
+
+    let mut compacted = vec![];
+    match jpeg.encoder().write_to(&mut compacted) {
+        Ok(_) => compacted,
+        Err(_) => in_file,
+    }
+}
+
+unsafe fn compress_to_size(
+    samples: &Samples,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, io::Error> {
+    let max_output_size = parameters.output_size as usize;
+    let mut subsamplings = vec![parameters.jpeg.chroma_subsampling];
+    // Auto already means 4:2:0 for colour images, so only the lighter modes get a fallback
+    if matches!(parameters.jpeg.chroma_subsampling, ChromaSubsampling::CS444 | ChromaSubsampling::CS422) {
+        subsamplings.push(ChromaSubsampling::CS420);
+    }
+
+    for subsampling in subsamplings {
+        let mut search_parameters = *parameters;
+        search_parameters.jpeg.chroma_subsampling = subsampling;
+        let result = search_quality(1, parameters.jpeg.quality, max_output_size, |quality| {
+            search_parameters.jpeg.quality = quality;
+            lossy(samples, &search_parameters)
+        })?;
+
+        if let Some(compressed) = result {
+            return Ok(compressed);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Cannot compress JPEG to {} bytes", max_output_size),
+    ))
+}
+
+unsafe fn compress_to_dssim(
+    samples: &Samples,
+    reference: &Reference,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, io::Error> {
+    let mut search_parameters = *parameters;
+    search_lowest_quality(1, parameters.jpeg.quality, |quality| {
+        search_parameters.jpeg.quality = quality;
+        let compressed = lossy(samples, &search_parameters)?;
+        let dssim = reference.compare(&compressed)?;
+        Ok((compressed, dssim <= parameters.max_dssim))
+    })
 }
 
 unsafe fn lossless(
     in_file: Vec<u8>,
     parameters: &CSParameters,
-) -> Result<Vec<u8>, io::Error> {
+) -> Result<(Vec<u8>, Option<Crop>), io::Error> {
     let mut src_info: jpeg_decompress_struct = mem::zeroed();
 
     let mut src_err = mem::zeroed();
@@ -81,7 +625,7 @@ unsafe fn lossless(
 
     jpeg_mem_src(&mut src_info, in_file.as_ptr(), in_file.len() as _);
 
-    if parameters.keep_metadata {
+    if parameters.metadata.keeps_any() {
         jpeg_save_markers(&mut src_info, 0xFE, 0xFFFF);
         for m in 0..16 {
             jpeg_save_markers(&mut src_info, 0xE0 + m, 0xFFFF);
@@ -90,9 +634,67 @@ unsafe fn lossless(
 
     jpeg_read_header(&mut src_info, true as boolean);
 
+    let mut transform_info: jpeg_transform_info = mem::zeroed();
+    transform_info.transform = match parameters.jpeg.transform {
+        Transform::None => JXFORM_NONE,
+        Transform::FlipHorizontal => JXFORM_FLIP_H,
+        Transform::FlipVertical => JXFORM_FLIP_V,
+        Transform::Transpose => JXFORM_TRANSPOSE,
+        Transform::Transverse => JXFORM_TRANSVERSE,
+        Transform::Rotate90 => JXFORM_ROT_90,
+        Transform::Rotate180 => JXFORM_ROT_180,
+        Transform::Rotate270 => JXFORM_ROT_270,
+    };
+    // Without trimming, partial MCUs on the right/bottom edges are left where they are
+    transform_info.trim = parameters.jpeg.trim as boolean;
+    // Keeps only the Y component of YCbCr images. transupp cannot reduce anything else, or luma that is
+    // subsampled itself, so those are left as they are
+    let luma = &*src_info.comp_info;
+    transform_info.force_grayscale = (parameters.jpeg.grayscale == Grayscale::On
+        && src_info.jpeg_color_space == JCS_YCbCr
+        && src_info.num_components == 3
+        && luma.h_samp_factor == src_info.max_h_samp_factor
+        && luma.v_samp_factor == src_info.max_v_samp_factor) as boolean;
+    if let Some(crop) = parameters.jpeg.crop {
+        let (width, height) = if parameters.jpeg.transform.swaps_axes() {
+            (src_info.image_height, src_info.image_width)
+        } else {
+            (src_info.image_width, src_info.image_height)
+        };
+        if crop.x >= width || crop.y >= height {
+            jpeg_destroy_compress(&mut dst_info);
+            jpeg_destroy_decompress(&mut src_info);
+            return Err(io::Error::new(io::ErrorKind::Other, "Crop rectangle is outside the image"));
+        }
+
+        transform_info.crop = true as boolean;
+        transform_info.crop_xoffset = crop.x;
+        transform_info.crop_xoffset_set = JCROP_POS;
+        transform_info.crop_yoffset = crop.y;
+        transform_info.crop_yoffset_set = JCROP_POS;
+        transform_info.crop_width = crop.width.min(width - crop.x);
+        transform_info.crop_width_set = JCROP_POS;
+        transform_info.crop_height = crop.height.min(height - crop.y);
+        transform_info.crop_height_set = JCROP_POS;
+    }
+    jtransform_request_workspace(&mut src_info, &mut transform_info);
+    // The top left corner is moved to an iMCU boundary, keeping the bottom right one
+    let applied_crop = parameters.jpeg.crop.map(|_| Crop {
+        x: transform_info.x_crop_offset * transform_info.iMCU_sample_width as u32,
+        y: transform_info.y_crop_offset * transform_info.iMCU_sample_height as u32,
+        width: transform_info.output_width,
+        height: transform_info.output_height,
+    });
+
     let src_coef_arrays = jpeg_read_coefficients(&mut src_info);
     jpeg_copy_critical_parameters(&src_info, &mut dst_info);
-    let dst_coef_arrays = src_coef_arrays;
+    let dst_coef_arrays = jtransform_adjust_parameters(
+        &mut src_info,
+        &mut dst_info,
+        src_coef_arrays,
+        &mut transform_info,
+    );
+    set_scan_mode(parameters.jpeg.progressive, &mut dst_info);
 
     dst_info.optimize_coding = i32::from(true);
     let mut buf = ptr::null_mut();
@@ -100,10 +702,12 @@ unsafe fn lossless(
     jpeg_mem_dest(&mut dst_info, &mut buf, &mut buf_size);
     jpeg_write_coefficients(&mut dst_info, dst_coef_arrays);
 
-    if parameters.keep_metadata {
-        write_metadata(&mut src_info, &mut dst_info);
+    if parameters.metadata.keeps_any() {
+        write_metadata(&mut src_info, &mut dst_info, parameters);
     }
 
+    jtransform_execute_transform(&mut src_info, &mut dst_info, src_coef_arrays, &mut transform_info);
+
     jpeg_finish_compress(&mut dst_info);
     jpeg_destroy_compress(&mut dst_info);
     jpeg_finish_decompress(&mut src_info);
@@ -118,29 +722,70 @@ unsafe fn lossless(
 
     free(buf as *mut c_void);
 
-    Ok(result)
+    Ok((result, applied_crop))
 }
 
-unsafe fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+/// Checks whether every Cb and Cr block of a YCbCr JPEG stays within `NEUTRAL_CHROMA_TOLERANCE`
+/// of neutral grey, looking at the dequantized coefficients only.
+unsafe fn has_neutral_chroma(in_file: &[u8]) -> bool {
     let mut src_info: jpeg_decompress_struct = mem::zeroed();
     let mut src_err = mem::zeroed();
-    let mut dst_info: jpeg_compress_struct = mem::zeroed();
-    let mut dst_err = mem::zeroed();
 
     src_info.common.err = jpeg_std_error(&mut src_err);
     (*src_info.common.err).error_exit = Some(error_handler);
     (*src_info.common.err).output_message = Some(error_message_handler);
 
-    dst_info.common.err = jpeg_std_error(&mut dst_err);
-    (*dst_info.common.err).error_exit = Some(error_handler);
-    (*dst_info.common.err).output_message = Some(error_message_handler);
-
     jpeg_create_decompress(&mut src_info);
-    jpeg_create_compress(&mut dst_info);
+    jpeg_mem_src(&mut src_info, in_file.as_ptr(), in_file.len() as _);
+    jpeg_read_header(&mut src_info, true as boolean);
 
+    if src_info.jpeg_color_space != JCS_YCbCr || src_info.num_components != 3 {
+        jpeg_destroy_decompress(&mut src_info);
+        return false;
+    }
+
+    let coef_arrays = jpeg_read_coefficients(&mut src_info);
+    let access_virt_barray = (*src_info.common.mem).access_virt_barray.unwrap();
+    let mut neutral = true;
+    'components: for ci in 1..3 {
+        let component = &*src_info.comp_info.add(ci);
+        let quantval = (*component.quant_table).quantval;
+        for row in 0..component.height_in_blocks {
+            let blocks = *access_virt_barray(&mut src_info.common, *coef_arrays.add(ci), row, 1, false as boolean);
+            for b in 0..component.width_in_blocks as usize {
+                // A sample deviates by at most a DC coefficient over 8 plus each AC coefficient over 4,
+                // summed here in eighths
+                let deviation: i32 = (*blocks.add(b))
+                    .iter()
+                    .zip(quantval.iter())
+                    .enumerate()
+                    .map(|(k, (coef, q))| (*coef as i32 * *q as i32).abs() * if k == 0 { 1 } else { 2 })
+                    .sum();
+                if deviation > NEUTRAL_CHROMA_TOLERANCE * 8 {
+                    neutral = false;
+                    break 'components;
+                }
+            }
+        }
+    }
+
+    jpeg_destroy_decompress(&mut src_info);
+    neutral
+}
+
+/// Decodes to the stored colour space, or to grayscale, or to RGB/CMYK for colour management with `to_rgb`.
+unsafe fn decode(in_file: &[u8], keep_markers: bool, grayscale: bool, to_rgb: bool) -> Samples {
+    let mut src_info: jpeg_decompress_struct = mem::zeroed();
+    let mut src_err = mem::zeroed();
+
+    src_info.common.err = jpeg_std_error(&mut src_err);
+    (*src_info.common.err).error_exit = Some(error_handler);
+    (*src_info.common.err).output_message = Some(error_message_handler);
+
+    jpeg_create_decompress(&mut src_info);
     jpeg_mem_src(&mut src_info, in_file.as_ptr(), in_file.len() as _);
 
-    if parameters.keep_metadata {
+    if keep_markers {
         jpeg_save_markers(&mut src_info, 0xFE, 0xFFFF);
         for m in 0..16 {
             jpeg_save_markers(&mut src_info, 0xE0 + m, 0xFFFF);
@@ -151,7 +796,15 @@ unsafe fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>,
 
     let width = src_info.image_width;
     let height = src_info.image_height;
-    let color_space = src_info.jpeg_color_space;
+    let color_space = match src_info.jpeg_color_space {
+        JCS_YCbCr if grayscale => JCS_GRAYSCALE,
+        JCS_YCbCr if to_rgb => JCS_RGB,
+        JCS_YCCK if to_rgb => JCS_CMYK,
+        stored => stored,
+    };
+    // saw_Adobe_marker is private in mozjpeg-sys
+    let inverted = color_space == JCS_CMYK
+        && segments(in_file).iter().any(|(marker, data)| *marker == 0xEE && data.starts_with(b"Adobe"));
     src_info.out_color_space = color_space;
     jpeg_start_decompress(&mut src_info);
     let row_stride = src_info.image_width as usize * src_info.output_components as usize;
@@ -165,7 +818,39 @@ unsafe fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>,
         jpeg_read_scanlines(&mut src_info, jsamparray.as_mut_ptr(), 1);
     }
 
-    let input_components = match color_space {
+    let mut markers = vec![];
+    let mut marker = src_info.marker_list;
+    while !marker.is_null() {
+        let data = std::slice::from_raw_parts((*marker).data, (*marker).data_length as usize);
+        markers.push(((*marker).marker as c_int, data.to_vec()));
+        marker = (*marker).next;
+    }
+
+    jpeg_finish_decompress(&mut src_info);
+    jpeg_destroy_decompress(&mut src_info);
+
+    Samples {
+        buffer,
+        width,
+        height,
+        color_space,
+        inverted,
+        markers,
+        source_size: (width, height),
+    }
+}
+
+unsafe fn lossy(samples: &Samples, parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    let mut dst_info: jpeg_compress_struct = mem::zeroed();
+    let mut dst_err = mem::zeroed();
+
+    dst_info.common.err = jpeg_std_error(&mut dst_err);
+    (*dst_info.common.err).error_exit = Some(error_handler);
+    (*dst_info.common.err).output_message = Some(error_message_handler);
+
+    jpeg_create_compress(&mut dst_info);
+
+    let input_components = match samples.color_space {
         JCS_GRAYSCALE => 1,
         JCS_RGB => 3,
         JCS_YCbCr => 3,
@@ -177,10 +862,15 @@ unsafe fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>,
     let mut buf = mem::zeroed();
     jpeg_mem_dest(&mut dst_info, &mut buf, &mut buf_size);
 
-    dst_info.image_width = width;
-    dst_info.image_height = height;
-    dst_info.in_color_space = color_space;
+    dst_info.image_width = samples.width;
+    dst_info.image_height = samples.height;
+    dst_info.in_color_space = samples.color_space;
     dst_info.input_components = input_components as c_int;
+    let profile = match parameters.jpeg.compression_profile {
+        CompressionProfile::MaxCompression => JCP_MAX_COMPRESSION,
+        CompressionProfile::Fastest => JCP_FASTEST,
+    };
+    jpeg_c_set_int_param(&mut dst_info, JINT_COMPRESS_PROFILE, profile as c_int);
     jpeg_set_defaults(&mut dst_info);
 
     if input_components == 3 && parameters.jpeg.chroma_subsampling != ChromaSubsampling::Auto {
@@ -190,26 +880,27 @@ unsafe fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>,
     let row_stride = dst_info.image_width as usize * dst_info.input_components as usize;
     dst_info.dct_method = J_DCT_METHOD::JDCT_ISLOW;
     dst_info.optimize_coding = i32::from(true);
-    jpeg_set_quality(
-        &mut dst_info,
-        parameters.jpeg.quality as i32,
-        false as boolean,
-    );
+    if parameters.jpeg.compression_profile == CompressionProfile::MaxCompression {
+        jpeg_c_set_bool_param(&mut dst_info, JBOOLEAN_TRELLIS_QUANT, parameters.jpeg.trellis as boolean);
+        jpeg_c_set_bool_param(&mut dst_info, JBOOLEAN_TRELLIS_QUANT_DC, parameters.jpeg.trellis_dc as boolean);
+        jpeg_c_set_bool_param(&mut dst_info, JBOOLEAN_OVERSHOOT_DERINGING, parameters.jpeg.overshoot_deringing as boolean);
+    }
+    jpeg_c_set_int_param(&mut dst_info, JINT_BASE_QUANT_TBL_IDX, parameters.jpeg.quant_table as c_int);
+    set_quality(parameters.jpeg.quality, parameters.jpeg.chroma_quality, &mut dst_info);
+    set_scan_mode(parameters.jpeg.progressive, &mut dst_info);
 
     jpeg_start_compress(&mut dst_info, true as boolean);
 
-    if parameters.keep_metadata {
-        write_metadata(&mut src_info, &mut dst_info);
+    for (marker, data) in &samples.markers {
+        write_marker(&mut dst_info, *marker, data, samples.source_size, parameters);
     }
 
     while dst_info.next_scanline < dst_info.image_height {
         let offset = dst_info.next_scanline as usize * row_stride;
-        let jsamparray = [buffer[offset..].as_ptr()];
+        let jsamparray = [samples.buffer[offset..].as_ptr()];
         jpeg_write_scanlines(&mut dst_info, jsamparray.as_ptr(), 1);
     }
 
-    jpeg_finish_decompress(&mut src_info);
-    jpeg_destroy_decompress(&mut src_info);
     jpeg_finish_compress(&mut dst_info);
     jpeg_destroy_compress(&mut dst_info);
 
@@ -225,21 +916,24 @@ unsafe fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>,
     Ok(result)
 }
 
-fn extract_metadata(image: Vec<u8>) -> (Option<img_parts::Bytes>, Option<img_parts::Bytes>) {
+/// ICC profile and EXIF of a JPEG, PNG or WebP file, an error when its structure cannot be parsed.
+pub fn extract_metadata(image: Vec<u8>) -> Result<(Option<img_parts::Bytes>, Option<img_parts::Bytes>), io::Error> {
     let (iccp, exif) = DynImage::from_bytes(image.into())
-        .expect("image loaded")
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
         .map_or((None, None), |dyn_image| {
             (dyn_image.icc_profile(), dyn_image.exif())
         });
 
-    (iccp, exif)
+    Ok((iccp, exif))
 }
 
-//TODO if image is resized, change "PixelXDimension" and "PixelYDimension"
-fn save_metadata(
+/// Attaches ICC and EXIF to an encoded JPEG, PNG or WebP, with the EXIF dimensions updated to
+/// the ones of `image_buffer`. `source` is the size of the image the EXIF comes from.
+pub fn save_metadata(
     image_buffer: Vec<u8>,
     iccp: Option<img_parts::Bytes>,
     exif: Option<img_parts::Bytes>,
+    source: (u32, u32),
 ) -> Vec<u8> {
     if iccp.is_some() || exif.is_some() {
         let mut dyn_image = match DynImage::from_bytes(img_parts::Bytes::from(image_buffer.clone()))
@@ -251,6 +945,15 @@ fn save_metadata(
             Err(_) => return image_buffer,
         };
 
+        let dimensions = image::io::Reader::new(io::Cursor::new(&image_buffer))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        let exif = match (exif, dimensions) {
+            (Some(exif), Some((width, height))) => Some(exif::update_dimensions(&exif, source, width, height).into()),
+            (exif, _) => exif,
+        };
+
         dyn_image.set_icc_profile(iccp);
         dyn_image.set_exif(exif);
 
@@ -264,20 +967,60 @@ fn save_metadata(
     }
 }
 
-unsafe fn write_metadata(src_info: &mut jpeg_decompress_struct, dst_info: &mut jpeg_compress_struct) {
+unsafe fn write_metadata(
+    src_info: &mut jpeg_decompress_struct,
+    dst_info: &mut jpeg_compress_struct,
+    parameters: &CSParameters,
+) {
     let mut marker = src_info.marker_list;
+    let source = (src_info.image_width, src_info.image_height);
 
     while !marker.is_null() {
-        jpeg_write_marker(
-            dst_info,
-            (*marker).marker as i32,
-            (*marker).data,
-            (*marker).data_length,
-        );
+        let data = std::slice::from_raw_parts((*marker).data, (*marker).data_length as usize);
+        write_marker(dst_info, (*marker).marker as c_int, data, source, parameters);
         marker = (*marker).next;
     }
 }
 
+/// Writes a saved marker if the metadata policy keeps it, bringing EXIF in line with the output geometry.
+/// `source` is the size of the image the marker was read from.
+unsafe fn write_marker(
+    dst_info: &mut jpeg_compress_struct,
+    marker: c_int,
+    data: &[u8],
+    source: (u32, u32),
+    parameters: &CSParameters,
+) {
+    let policy = &parameters.metadata;
+    let keep = match marker {
+        // libjpeg writes its own JFIF and Adobe markers
+        0xE0 if data.starts_with(b"JFIF\0") => false,
+        0xEE if data.starts_with(b"Adobe") => false,
+        0xE1 if data.starts_with(b"Exif\0\0") => {
+            let exif = match exif::filter(data, policy) {
+                Some(e) => e,
+                None => return,
+            };
+            let mut exif = exif::update_dimensions(&exif, source, dst_info.image_width, dst_info.image_height);
+            if parameters.jpeg.transform != Transform::None {
+                // The thumbnail keeps the old orientation
+                exif = exif::remove_thumbnail(&exif);
+            }
+            jpeg_write_marker(dst_info, marker, exif.as_ptr(), exif.len() as c_uint);
+            return;
+        }
+        0xE1 if data.starts_with(b"http://ns.adobe.com/x") => policy.xmp,
+        0xE2 if data.starts_with(ICC_MARKER) => policy.icc,
+        0xED if data.starts_with(b"Photoshop 3.0\0") => policy.iptc,
+        0xFE => policy.comments,
+        _ => policy.other,
+    };
+
+    if keep {
+        jpeg_write_marker(dst_info, marker, data.as_ptr(), data.len() as c_uint);
+    }
+}
+
 unsafe fn set_chroma_subsampling(subsampling: ChromaSubsampling, dst_info: &mut jpeg_compress_struct)
 {
     (*dst_info.comp_info.add(1)).h_samp_factor = 1;
@@ -305,9 +1048,137 @@ unsafe fn set_chroma_subsampling(subsampling: ChromaSubsampling, dst_info: &mut
     }
 }
 
+/// Scales the base quantization tables, chroma tables use `chroma_quality` unless it is 0.
+unsafe fn set_quality(quality: u32, chroma_quality: u32, dst_info: &mut jpeg_compress_struct) {
+    if chroma_quality != 0 && chroma_quality != quality {
+        jpeg_set_quality(dst_info, chroma_quality as c_int, false as boolean);
+        let chroma_table = (*dst_info.quant_tbl_ptrs[1]).quantval;
+        jpeg_set_quality(dst_info, quality as c_int, false as boolean);
+        (*dst_info.quant_tbl_ptrs[1]).quantval = chroma_table;
+    } else {
+        jpeg_set_quality(dst_info, quality as c_int, false as boolean);
+    }
+}
+
+/// Selects between mozjpeg's optimised progressive scan script and a single baseline scan.
+/// Must be called once the component layout of `dst_info` is final.
+unsafe fn set_scan_mode(progressive: bool, dst_info: &mut jpeg_compress_struct) {
+    jpeg_c_set_bool_param(dst_info, JBOOLEAN_OPTIMIZE_SCANS, progressive as boolean);
+    if progressive {
+        jpeg_simple_progression(dst_info);
+    } else {
+        dst_info.scan_info = ptr::null();
+        dst_info.num_scans = 0;
+    }
+}
+
 unsafe extern "C" fn error_handler(cinfo: &mut jpeg_common_struct) {
-    JPEG_ERROR = (*cinfo.err).msg_code;
-    panic!("Internal JPEG error: {}", JPEG_ERROR);
+    let error_code = (*cinfo.err).msg_code;
+    JPEG_ERROR = error_code;
+    panic!("Internal JPEG error: {}", error_code);
+}
+
+unsafe extern "C" fn error_message_handler(_cinfo: &mut jpeg_common_struct) {}
+#[test]
+fn flatten_composites_over_matte() {
+    let image = DynamicImage::ImageRgba8(image::RgbaImage::from_raw(3, 1, vec![
+        255, 0, 0, 255,
+        255, 0, 0, 0,
+        0, 0, 255, 128,
+    ]).unwrap());
+
+    let flattened = flatten(&image, [255, 255, 255]).to_rgb8().into_raw();
+    assert_eq!(flattened, vec![255, 0, 0, 255, 255, 255, 127, 127, 255])
+}
+
+#[test]
+fn estimates_the_encoded_quality() {
+    let (width, height) = (64, 48);
+    let buffer = (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            [(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8]
+        })
+        .collect();
+    let samples = Samples {
+        buffer,
+        width,
+        height,
+        color_space: JCS_RGB,
+        inverted: false,
+        markers: vec![],
+        source_size: (width, height),
+    };
+
+    for (quant_table, quality) in [(3, 80), (3, 45), (0, 92), (5, 60)] {
+        let mut parameters = crate::initialize_parameters();
+        parameters.jpeg.quant_table = quant_table;
+        parameters.jpeg.quality = quality;
+        let compressed = unsafe { lossy(&samples, &parameters) }.unwrap();
+        assert_eq!(estimate_quality(&compressed, quant_table).unwrap(), quality);
+    }
 }
 
-unsafe extern "C" fn error_message_handler(_cinfo: &mut jpeg_common_struct) {}
\ No newline at end of file
+#[test]
+fn faint_chroma_is_not_neutral() {
+    use std::f64::consts::PI;
+
+    let (width, height) = (16, 16);
+    // Grey with a Cb wave peaking at 2.4 levels, a single AC coefficient per block
+    let encode_with_cb = |amplitude: f64| {
+        let buffer = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = ((i % width) % 8, (i / width) % 8);
+                let cb = amplitude
+                    * ((2 * x + 1) as f64 * PI / 16.0).cos()
+                    * ((2 * y + 1) as f64 * PI / 16.0).cos();
+                [128, (128.0 - 0.344136 * cb).round() as u8, (128.0 + 1.772 * cb).round() as u8]
+            })
+            .collect();
+        let samples = Samples {
+            buffer,
+            width,
+            height,
+            color_space: JCS_RGB,
+            inverted: false,
+            markers: vec![],
+            source_size: (width, height),
+        };
+        let mut parameters = crate::initialize_parameters();
+        parameters.jpeg.quality = 100;
+        parameters.jpeg.chroma_subsampling = ChromaSubsampling::CS444;
+        unsafe { lossy(&samples, &parameters) }.unwrap()
+    };
+
+    unsafe {
+        assert!(has_neutral_chroma(&encode_with_cb(0.0)));
+        assert!(!has_neutral_chroma(&encode_with_cb(2.5)));
+    }
+}
+
+#[test]
+fn grayscale_leaves_cmyk_alone() {
+    let (width, height) = (16, 16);
+    let samples = Samples {
+        buffer: (0..width * height * 4).map(|i| (i * 7) as u8).collect(),
+        width,
+        height,
+        color_space: JCS_CMYK,
+        inverted: false,
+        markers: vec![],
+        source_size: (width, height),
+    };
+    let mut parameters = crate::initialize_parameters();
+    let cmyk = unsafe { lossy(&samples, &parameters) }.unwrap();
+
+    parameters.optimize = true;
+    parameters.jpeg.grayscale = Grayscale::On;
+    let compressed = compress_to_memory(cmyk, &parameters).unwrap();
+    assert!(is_cmyk(&compressed));
+}
+
+#[test]
+fn truncated_metadata_is_an_error() {
+    // An APP1 segment claiming more bytes than the file has
+    assert!(extract_metadata(b"\xFF\xD8\xFF\xE1\x01\x00Exif".to_vec()).is_err());
+}
@@ -1,7 +1,8 @@
 #![allow(non_snake_case)]
+#![allow(clippy::io_other_error)]
 extern crate alloc;
-use jni::objects::{JClass, JObject};
-use jni::sys::{jbyteArray};
+use jni::objects::{JClass, JObject, JValue};
+use jni::sys::{jbyteArray, jobject};
 use jni::JNIEnv;
 
 #[cfg(target_os = "android")]
@@ -14,7 +15,10 @@ use std::error::Error;
 use std::ffi::CStr;
 use std::os::raw::c_char;
 use jni::signature::{Primitive, ReturnType};
-use crate::jpeg::ChromaSubsampling;
+use crate::convert::OutputFormat;
+use crate::jpeg::{ChromaSubsampling, CompressionProfile, Crop, Grayscale};
+use crate::orientation::Transform;
+use crate::resize::{ResizeFilter, ResizeFit, ResizeTarget};
 
 use crate::utils::{get_filetype, SupportedFileTypes};
 
@@ -25,35 +29,99 @@ fn init_logger() {
     );
 }
 
+mod color;
+mod convert;
+mod exif;
+mod icc;
+mod info;
 mod jpeg;
+mod orientation;
 mod png;
 mod resize;
+mod similarity;
+mod transupp;
 mod utils;
 mod webp;
 
 #[repr(C)]
 pub struct CCSParameters {
-    pub keep_metadata: bool,
+    pub keep_icc: bool,
+    pub keep_orientation: bool,
+    pub keep_exif: bool,
+    pub keep_gps: bool,
+    pub keep_serial_numbers: bool,
+    pub keep_maker_notes: bool,
+    pub keep_xmp: bool,
+    pub keep_iptc: bool,
+    pub keep_comments: bool,
+    pub keep_other_metadata: bool,
     pub jpeg_quality: u32,
     pub jpeg_chroma_subsampling: u32,
+    pub jpeg_avoid_generational_loss: bool,
+    pub jpeg_progressive: bool,
+    pub jpeg_trellis: bool,
+    pub jpeg_trellis_dc: bool,
+    pub jpeg_overshoot_deringing: bool,
+    pub jpeg_quant_table: u32,
+    pub jpeg_compression_profile: u32,
+    pub jpeg_chroma_quality: u32,
+    pub jpeg_transform: u32,
+    pub jpeg_trim: bool,
+    pub jpeg_crop_x: u32,
+    pub jpeg_crop_y: u32,
+    pub jpeg_crop_width: u32,
+    pub jpeg_crop_height: u32,
+    pub jpeg_grayscale: u32,
+    pub jpeg_cmyk_to_rgb: bool,
     pub png_quality: u32,
     pub png_force_zopfli: bool,
     pub webp_quality: u32,
     pub optimize: bool,
     pub width: u32,
     pub height: u32,
+    pub resize_fit: u32,
+    pub resize_target: u32,
+    pub resize_target_value: f64,
+    pub without_enlargement: bool,
+    pub resize_filter: u32,
+    pub linear_resize: bool,
+    pub output_size: u32,
+    pub max_dssim: f64,
+    pub output_format: u32,
+    pub matte_color: u32,
+    pub auto_orient: bool,
+    pub convert_to_srgb: bool,
 }
 
 #[repr(C)]
 pub struct CCSResult {
     pub success: bool,
     pub error_message: *const c_char,
+    pub source_jpeg_quality: u32,
+    pub crop_x: u32,
+    pub crop_y: u32,
+    pub crop_width: u32,
+    pub crop_height: u32,
 }
 
 #[derive(Copy, Clone)]
 pub struct JpegParameters {
     pub quality: u32,
-    pub chroma_subsampling: ChromaSubsampling
+    pub chroma_subsampling: ChromaSubsampling,
+    pub avoid_generational_loss: bool,
+    pub progressive: bool,
+    pub trellis: bool,
+    pub trellis_dc: bool,
+    pub overshoot_deringing: bool,
+    pub quant_table: u32,
+    pub compression_profile: CompressionProfile,
+    pub chroma_quality: u32,
+    pub transform: Transform,
+    pub trim: bool,
+    pub crop: Option<Crop>,
+    pub grayscale: Grayscale,
+    /// Re-encodes CMYK and YCCK JPEGs as RGB, through their ICC profile when they have one
+    pub cmyk_to_rgb: bool,
 }
 
 #[derive(Copy, Clone)]
@@ -67,22 +135,125 @@ pub struct WebPParameters {
     pub quality: u32,
 }
 
+/// Which metadata is carried over to the output, each kind is kept or stripped on its own.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MetadataPolicy {
+    /// Embedded ICC colour profiles
+    pub icc: bool,
+    /// The EXIF orientation tag
+    pub orientation: bool,
+    /// EXIF tags not covered below: camera model, exposure, dates, the thumbnail...
+    pub exif: bool,
+    pub gps: bool,
+    /// Camera body and lens serial numbers
+    pub serial_numbers: bool,
+    pub maker_notes: bool,
+    pub xmp: bool,
+    pub iptc: bool,
+    /// JPEG COM segments and PNG text chunks
+    pub comments: bool,
+    /// Any other JPEG application segment or PNG ancillary chunk that does not affect rendering
+    pub other: bool,
+}
+
+impl MetadataPolicy {
+    pub const KEEP_ALL: MetadataPolicy = MetadataPolicy {
+        icc: true,
+        orientation: true,
+        exif: true,
+        gps: true,
+        serial_numbers: true,
+        maker_notes: true,
+        xmp: true,
+        iptc: true,
+        comments: true,
+        other: true,
+    };
+
+    pub const STRIP_ALL: MetadataPolicy = MetadataPolicy {
+        icc: false,
+        orientation: false,
+        exif: false,
+        gps: false,
+        serial_numbers: false,
+        maker_notes: false,
+        xmp: false,
+        iptc: false,
+        comments: false,
+        other: false,
+    };
+
+    pub fn keeps_any(&self) -> bool {
+        *self != MetadataPolicy::STRIP_ALL
+    }
+
+    /// Whether EXIF can be copied without looking inside.
+    pub fn keeps_all_exif(&self) -> bool {
+        self.orientation && self.exif && self.gps && self.serial_numbers && self.maker_notes
+    }
+
+    /// Applies the policy to metadata from `jpeg::extract_metadata`.
+    pub fn filter(
+        &self,
+        (iccp, exif): (Option<img_parts::Bytes>, Option<img_parts::Bytes>),
+    ) -> (Option<img_parts::Bytes>, Option<img_parts::Bytes>) {
+        let iccp = iccp
+            .filter(|_| self.icc)
+            .map(|iccp| icc::compact(&iccp).map(img_parts::Bytes::from).unwrap_or(iccp));
+        let exif = exif.and_then(|exif| exif::filter(&exif, self)).map(img_parts::Bytes::from);
+        (iccp, exif)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct CSParameters {
     pub jpeg: JpegParameters,
     pub png: PngParameters,
     pub webp: WebPParameters,
-    pub keep_metadata: bool,
+    pub metadata: MetadataPolicy,
     pub optimize: bool,
     pub width: u32,
     pub height: u32,
+    pub resize_fit: ResizeFit,
+    pub resize_target: ResizeTarget,
+    /// Leaves images the target would enlarge as they are
+    pub without_enlargement: bool,
+    pub resize_filter: ResizeFilter,
+    /// Resamples in linear light, keeps fine detail and thin lines from darkening
+    pub linear_resize: bool,
     pub output_size: u32,
+    pub max_dssim: f64,
+    pub output_format: OutputFormat,
+    pub matte_color: [u8; 3],
+    pub auto_orient: bool,
+    /// Converts pixels tagged with a non-sRGB ICC profile to sRGB and drops the profile
+    pub convert_to_srgb: bool,
+}
+
+/// Information gathered while compressing, returned to the caller.
+#[derive(Copy, Clone, Default)]
+pub struct CSReport {
+    pub source_jpeg_quality: Option<u32>,
+    pub crop: Option<Crop>,
 }
 
 pub fn initialize_parameters() -> CSParameters {
     let jpeg = JpegParameters {
         quality: 80,
-        chroma_subsampling: ChromaSubsampling::Auto
+        chroma_subsampling: ChromaSubsampling::Auto,
+        avoid_generational_loss: false,
+        progressive: true,
+        trellis: true,
+        trellis_dc: true,
+        overshoot_deringing: true,
+        quant_table: 3,
+        compression_profile: CompressionProfile::MaxCompression,
+        chroma_quality: 0,
+        transform: Transform::None,
+        trim: true,
+        crop: None,
+        grayscale: Grayscale::Off,
+        cmyk_to_rgb: false,
     };
 
     let png = PngParameters {
@@ -96,24 +267,39 @@ pub fn initialize_parameters() -> CSParameters {
         jpeg,
         png,
         webp,
-        keep_metadata: false,
+        metadata: MetadataPolicy::STRIP_ALL,
         optimize: false,
         width: 0,
         height: 0,
+        resize_fit: ResizeFit::Fill,
+        resize_target: ResizeTarget::Box,
+        without_enlargement: false,
+        resize_filter: ResizeFilter::Lanczos3,
+        linear_resize: false,
         output_size: 0,
+        max_dssim: 0.0,
+        output_format: OutputFormat::Original,
+        matte_color: [255, 255, 255],
+        auto_orient: false,
+        convert_to_srgb: false,
     }
 }
 
-fn c_return_result(result: Result<(), Box<dyn Error>>) -> CCSResult {
+fn c_return_result(result: Result<CSReport, Box<dyn Error>>) -> CCSResult {
     let mut error_message = CString::new("").unwrap();
 
     match result {
-        Ok(_) => {
+        Ok(report) => {
             let em_pointer = error_message.as_ptr();
             std::mem::forget(error_message);
             CCSResult {
                 success: true,
                 error_message: em_pointer,
+                source_jpeg_quality: report.source_jpeg_quality.unwrap_or(0),
+                crop_x: report.crop.map_or(0, |c| c.x),
+                crop_y: report.crop.map_or(0, |c| c.y),
+                crop_width: report.crop.map_or(0, |c| c.width),
+                crop_height: report.crop.map_or(0, |c| c.height),
             }
         }
         Err(e) => {
@@ -123,11 +309,19 @@ fn c_return_result(result: Result<(), Box<dyn Error>>) -> CCSResult {
             CCSResult {
                 success: false,
                 error_message: em_pointer,
+                source_jpeg_quality: 0,
+                crop_x: 0,
+                crop_y: 0,
+                crop_width: 0,
+                crop_height: 0,
             }
         }
     }
 }
 
+/// # Safety
+///
+/// Runs the native codecs on `inputData`; the returned `error_message` is leaked and owned by the caller.
 pub unsafe fn my_compress(
     inputData: Vec<u8>,
     outData: &mut Vec<u8>,
@@ -136,13 +330,105 @@ pub unsafe fn my_compress(
     let mut parameters = initialize_parameters();
 
     parameters.jpeg.quality = params.jpeg_quality;
+    parameters.jpeg.avoid_generational_loss = params.jpeg_avoid_generational_loss;
+    parameters.jpeg.progressive = params.jpeg_progressive;
+    parameters.jpeg.trellis = params.jpeg_trellis;
+    parameters.jpeg.trellis_dc = params.jpeg_trellis_dc;
+    parameters.jpeg.overshoot_deringing = params.jpeg_overshoot_deringing;
+    parameters.jpeg.quant_table = params.jpeg_quant_table;
+    parameters.jpeg.compression_profile = match params.jpeg_compression_profile {
+        1 => CompressionProfile::Fastest,
+        _ => CompressionProfile::MaxCompression,
+    };
+    parameters.jpeg.chroma_quality = params.jpeg_chroma_quality;
+    parameters.jpeg.transform = match params.jpeg_transform {
+        1 => Transform::FlipHorizontal,
+        2 => Transform::FlipVertical,
+        3 => Transform::Transpose,
+        4 => Transform::Transverse,
+        5 => Transform::Rotate90,
+        6 => Transform::Rotate180,
+        7 => Transform::Rotate270,
+        _ => Transform::None,
+    };
+    parameters.jpeg.trim = params.jpeg_trim;
+    parameters.jpeg.cmyk_to_rgb = params.jpeg_cmyk_to_rgb;
+    parameters.jpeg.grayscale = match params.jpeg_grayscale {
+        1 => Grayscale::On,
+        2 => Grayscale::Auto,
+        _ => Grayscale::Off,
+    };
+    parameters.jpeg.chroma_subsampling = match params.jpeg_chroma_subsampling {
+        0 => ChromaSubsampling::CS444,
+        1 => ChromaSubsampling::CS422,
+        2 => ChromaSubsampling::CS420,
+        3 => ChromaSubsampling::CS411,
+        _ => ChromaSubsampling::Auto,
+    };
+    if params.jpeg_crop_width > 0 && params.jpeg_crop_height > 0 {
+        parameters.jpeg.crop = Some(Crop {
+            x: params.jpeg_crop_x,
+            y: params.jpeg_crop_y,
+            width: params.jpeg_crop_width,
+            height: params.jpeg_crop_height,
+        });
+    }
     parameters.png.quality = params.png_quality;
     parameters.optimize = params.optimize;
-    parameters.keep_metadata = params.keep_metadata;
+    parameters.metadata = MetadataPolicy {
+        icc: params.keep_icc,
+        orientation: params.keep_orientation,
+        exif: params.keep_exif,
+        gps: params.keep_gps,
+        serial_numbers: params.keep_serial_numbers,
+        maker_notes: params.keep_maker_notes,
+        xmp: params.keep_xmp,
+        iptc: params.keep_iptc,
+        comments: params.keep_comments,
+        other: params.keep_other_metadata,
+    };
     parameters.png.force_zopfli = params.png_force_zopfli;
     parameters.webp.quality = params.webp_quality;
     parameters.width = params.width;
     parameters.height = params.height;
+    parameters.resize_fit = match params.resize_fit {
+        1 => ResizeFit::Contain,
+        2 => ResizeFit::Cover,
+        3 => ResizeFit::Outside,
+        4 => ResizeFit::Pad,
+        _ => ResizeFit::Fill,
+    };
+    parameters.resize_target = match params.resize_target {
+        1 => ResizeTarget::LongEdge(params.resize_target_value as u32),
+        2 => ResizeTarget::ShortEdge(params.resize_target_value as u32),
+        3 => ResizeTarget::Megapixels(params.resize_target_value),
+        4 => ResizeTarget::Percentage(params.resize_target_value),
+        _ => ResizeTarget::Box,
+    };
+    parameters.without_enlargement = params.without_enlargement;
+    parameters.resize_filter = match params.resize_filter {
+        0 => ResizeFilter::Nearest,
+        1 => ResizeFilter::Triangle,
+        2 => ResizeFilter::CatmullRom,
+        3 => ResizeFilter::Mitchell,
+        _ => ResizeFilter::Lanczos3,
+    };
+    parameters.linear_resize = params.linear_resize;
+    parameters.output_size = params.output_size;
+    parameters.max_dssim = params.max_dssim;
+    parameters.output_format = match params.output_format {
+        1 => OutputFormat::Jpeg,
+        2 => OutputFormat::Png,
+        3 => OutputFormat::WebP,
+        _ => OutputFormat::Original,
+    };
+    parameters.matte_color = [
+        (params.matte_color >> 16) as u8,
+        (params.matte_color >> 8) as u8,
+        params.matte_color as u8,
+    ];
+    parameters.auto_orient = params.auto_orient;
+    parameters.convert_to_srgb = params.convert_to_srgb;
 
     c_return_result(compress(
         inputData,
@@ -155,13 +441,46 @@ pub fn compress(
     inputData: Vec<u8>,
     outData: &mut Vec<u8>,
     parameters: &CSParameters,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<CSReport, Box<dyn Error>> {
     validate_parameters(parameters)?;
     let file_type = get_filetype(inputData.clone());
+    let mut report = CSReport::default();
+
+    if file_type == SupportedFileTypes::Jpeg {
+        report.source_jpeg_quality = jpeg::estimate_quality(&inputData, jpeg::ANNEX_K_TABLES).ok();
+    }
+
+    let output_type = match parameters.output_format {
+        OutputFormat::Original => file_type,
+        OutputFormat::Jpeg => SupportedFileTypes::Jpeg,
+        OutputFormat::Png => SupportedFileTypes::Png,
+        OutputFormat::WebP => SupportedFileTypes::WebP,
+    };
+    if output_type != file_type && file_type != SupportedFileTypes::Unkn {
+        report.crop = convert::convert(inputData, file_type, output_type, outData, parameters)?;
+        return Ok(report);
+    }
 
     match file_type {
         SupportedFileTypes::Jpeg => {
-            jpeg::compress(inputData.clone(), outData, parameters)?;
+            let mut jpeg_parameters = *parameters;
+            // Re-encoding at the same or a higher quality only adds loss, keep the coefficients instead
+            let quality_driven = parameters.output_size > 0 || parameters.max_dssim > 0.0;
+            if parameters.jpeg.avoid_generational_loss && !quality_driven {
+                // Compared on the scale of the tables the encoder uses, not the reported libjpeg one
+                let source_quality = jpeg::estimate_quality(&inputData, parameters.jpeg.quant_table).ok();
+                if let Some(source_quality) = source_quality.filter(|q| parameters.jpeg.quality >= *q) {
+                    log::info!("Source JPEG quality is {}, optimizing losslessly", source_quality);
+                    jpeg_parameters.optimize = true;
+                }
+            }
+            let mut in_file = inputData.clone();
+            if jpeg_parameters.jpeg.crop.is_some() {
+                let (cropped, applied_crop) = jpeg::crop(in_file, &mut jpeg_parameters)?;
+                in_file = cropped;
+                report.crop = applied_crop;
+            }
+            jpeg::compress(in_file, outData, &jpeg_parameters)?;
         }
         SupportedFileTypes::Png => {
             png::compress(inputData.clone(), outData, parameters)?;
@@ -172,7 +491,12 @@ pub fn compress(
         _ => return Err("Unknown file type".into()),
     }
 
-    Ok(())
+    Ok(report)
+}
+
+/// Reads format, geometry, colour and metadata details of an image without compressing it.
+pub fn analyze(inputData: &[u8]) -> Result<info::ImageInfo, Box<dyn Error>> {
+    Ok(info::analyze(inputData)?)
 }
 
 fn validate_parameters(parameters: &CSParameters) -> Result<(), Box<dyn Error>> {
@@ -183,6 +507,14 @@ fn validate_parameters(parameters: &CSParameters) -> Result<(), Box<dyn Error>>
         return Err("Invalid JPEG quality value".into());
     }
 
+    if parameters.jpeg.chroma_quality > 100 {
+        return Err("Invalid JPEG chroma quality value".into());
+    }
+
+    if parameters.jpeg.quant_table > 8 {
+        return Err("Invalid JPEG quantization table".into());
+    }
+
     if parameters.png.quality > 100 {
         return Err("Invalid PNG quality value".into());
     }
@@ -191,9 +523,25 @@ fn validate_parameters(parameters: &CSParameters) -> Result<(), Box<dyn Error>>
         return Err("Invalid WebP quality value".into());
     }
 
+    if parameters.max_dssim < 0.0 || parameters.max_dssim.is_nan() {
+        return Err("Invalid DSSIM threshold".into());
+    }
+
+    let target_value = match parameters.resize_target {
+        ResizeTarget::Box => 1.0,
+        ResizeTarget::LongEdge(edge) | ResizeTarget::ShortEdge(edge) => edge as f64,
+        ResizeTarget::Megapixels(value) | ResizeTarget::Percentage(value) => value,
+    };
+    if !(target_value > 0.0 && target_value.is_finite()) {
+        return Err("Invalid resize target value".into());
+    }
+
     Ok(())
 }
 
+/// # Safety
+///
+/// Must only be called by the JVM with a valid `JNIEnv`, byte array and `CCSParameter` object.
 #[no_mangle]
 pub unsafe extern "system" fn  Java_com_luis_bci_CaesiumNative_compressPic(
     env: JNIEnv,
@@ -210,19 +558,94 @@ pub unsafe extern "system" fn  Java_com_luis_bci_CaesiumNative_compressPic(
 
     let cl = env.find_class("com/luis/bci/CCSParameter").unwrap();
     // Get field ID from class CCSParameter
-    let f_keep_metadata = env.get_field_id(cl, "keep_metadata", "Z").unwrap();
+    let f_keep_icc = env.get_field_id(cl, "keep_icc", "Z").unwrap();
+    let f_keep_orientation = env.get_field_id(cl, "keep_orientation", "Z").unwrap();
+    let f_keep_exif = env.get_field_id(cl, "keep_exif", "Z").unwrap();
+    let f_keep_gps = env.get_field_id(cl, "keep_gps", "Z").unwrap();
+    let f_keep_serial_numbers = env.get_field_id(cl, "keep_serial_numbers", "Z").unwrap();
+    let f_keep_maker_notes = env.get_field_id(cl, "keep_maker_notes", "Z").unwrap();
+    let f_keep_xmp = env.get_field_id(cl, "keep_xmp", "Z").unwrap();
+    let f_keep_iptc = env.get_field_id(cl, "keep_iptc", "Z").unwrap();
+    let f_keep_comments = env.get_field_id(cl, "keep_comments", "Z").unwrap();
+    let f_keep_other_metadata = env.get_field_id(cl, "keep_other_metadata", "Z").unwrap();
     let f_jpeg_quality = env.get_field_id(cl, "jpeg_qu", "I").unwrap();
+    let f_jpeg_avoid_generational_loss = env.get_field_id(cl, "avoid_generational_loss", "Z").unwrap();
+    let f_jpeg_progressive = env.get_field_id(cl, "progressive", "Z").unwrap();
+    let f_jpeg_trellis = env.get_field_id(cl, "trellis", "Z").unwrap();
+    let f_jpeg_trellis_dc = env.get_field_id(cl, "trellis_dc", "Z").unwrap();
+    let f_jpeg_overshoot_deringing = env.get_field_id(cl, "overshoot_deringing", "Z").unwrap();
+    let f_jpeg_quant_table = env.get_field_id(cl, "quant_table", "I").unwrap();
+    let f_jpeg_chroma_quality = env.get_field_id(cl, "jpeg_chroma_qu", "I").unwrap();
+    let f_jpeg_trim = env.get_field_id(cl, "trim", "Z").unwrap();
+    let f_jpeg_crop_x = env.get_field_id(cl, "crop_x", "I").unwrap();
+    let f_jpeg_crop_y = env.get_field_id(cl, "crop_y", "I").unwrap();
+    let f_jpeg_crop_width = env.get_field_id(cl, "crop_width", "I").unwrap();
+    let f_jpeg_crop_height = env.get_field_id(cl, "crop_height", "I").unwrap();
+    let f_jpeg_cmyk_to_rgb = env.get_field_id(cl, "cmyk_to_rgb", "Z").unwrap();
     let f_png_quality = env.get_field_id(cl, "png_qu", "I").unwrap();
     let f_png_force_zopfli = env.get_field_id(cl, "png_force_zopfli", "Z").unwrap();
     let f_webp_quality = env.get_field_id(cl, "webp_qu", "I").unwrap();
     let f_optimize = env.get_field_id(cl, "optm", "Z").unwrap();
     let f_width = env.get_field_id(cl, "width", "I").unwrap();
     let f_height = env.get_field_id(cl, "height", "I").unwrap();
+    let f_output_size = env.get_field_id(cl, "output_size", "I").unwrap();
+    let f_max_dssim = env.get_field_id(cl, "max_dssim", "D").unwrap();
+    let f_resize_target_value = env.get_field_id(cl, "resize_target_value", "D").unwrap();
+    let f_without_enlargement = env.get_field_id(cl, "without_enlargement", "Z").unwrap();
+    let f_linear_resize = env.get_field_id(cl, "linear_resize", "Z").unwrap();
+    let f_matte_color = env.get_field_id(cl, "matte_color", "I").unwrap();
+    let f_auto_orient = env.get_field_id(cl, "auto_orient", "Z").unwrap();
+    let f_convert_to_srgb = env.get_field_id(cl, "convert_to_srgb", "Z").unwrap();
 
     // Read field value from by ID of class CCSParameter
-    let km: bool = env.get_field_unchecked(
+    let kicc: bool = env.get_field_unchecked(
+        conf,
+        f_keep_icc,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let kori: bool = env.get_field_unchecked(
+        conf,
+        f_keep_orientation,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let kexif: bool = env.get_field_unchecked(
+        conf,
+        f_keep_exif,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let kgps: bool = env.get_field_unchecked(
+        conf,
+        f_keep_gps,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let ksn: bool = env.get_field_unchecked(
+        conf,
+        f_keep_serial_numbers,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let kmn: bool = env.get_field_unchecked(
+        conf,
+        f_keep_maker_notes,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let kxmp: bool = env.get_field_unchecked(
+        conf,
+        f_keep_xmp,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let kiptc: bool = env.get_field_unchecked(
+        conf,
+        f_keep_iptc,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let kcom: bool = env.get_field_unchecked(
         conf,
-        f_keep_metadata,
+        f_keep_comments,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let koth: bool = env.get_field_unchecked(
+        conf,
+        f_keep_other_metadata,
         ReturnType::Primitive(Primitive::Boolean),
     ).unwrap().z().unwrap();
     let jq: u32 = env.get_field_unchecked(
@@ -230,6 +653,71 @@ pub unsafe extern "system" fn  Java_com_luis_bci_CaesiumNative_compressPic(
         f_jpeg_quality,
         ReturnType::Primitive(Primitive::Int),
     ).unwrap().i().unwrap() as u32;
+    let agl: bool = env.get_field_unchecked(
+        conf,
+        f_jpeg_avoid_generational_loss,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let prog: bool = env.get_field_unchecked(
+        conf,
+        f_jpeg_progressive,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let tq: bool = env.get_field_unchecked(
+        conf,
+        f_jpeg_trellis,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let tqdc: bool = env.get_field_unchecked(
+        conf,
+        f_jpeg_trellis_dc,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let od: bool = env.get_field_unchecked(
+        conf,
+        f_jpeg_overshoot_deringing,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let qt: u32 = env.get_field_unchecked(
+        conf,
+        f_jpeg_quant_table,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let jcq: u32 = env.get_field_unchecked(
+        conf,
+        f_jpeg_chroma_quality,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let trim: bool = env.get_field_unchecked(
+        conf,
+        f_jpeg_trim,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let cx: u32 = env.get_field_unchecked(
+        conf,
+        f_jpeg_crop_x,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let cy: u32 = env.get_field_unchecked(
+        conf,
+        f_jpeg_crop_y,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let cw: u32 = env.get_field_unchecked(
+        conf,
+        f_jpeg_crop_width,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let ch: u32 = env.get_field_unchecked(
+        conf,
+        f_jpeg_crop_height,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let c2r: bool = env.get_field_unchecked(
+        conf,
+        f_jpeg_cmyk_to_rgb,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
     let pq: u32 = env.get_field_unchecked(
         conf,
         f_png_quality,
@@ -260,6 +748,46 @@ pub unsafe extern "system" fn  Java_com_luis_bci_CaesiumNative_compressPic(
         f_height,
         ReturnType::Primitive(Primitive::Int),
     ).unwrap().i().unwrap() as u32;
+    let os: u32 = env.get_field_unchecked(
+        conf,
+        f_output_size,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let md: f64 = env.get_field_unchecked(
+        conf,
+        f_max_dssim,
+        ReturnType::Primitive(Primitive::Double),
+    ).unwrap().d().unwrap();
+    let mc: u32 = env.get_field_unchecked(
+        conf,
+        f_matte_color,
+        ReturnType::Primitive(Primitive::Int),
+    ).unwrap().i().unwrap() as u32;
+    let ao: bool = env.get_field_unchecked(
+        conf,
+        f_auto_orient,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let srgb: bool = env.get_field_unchecked(
+        conf,
+        f_convert_to_srgb,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let rtv: f64 = env.get_field_unchecked(
+        conf,
+        f_resize_target_value,
+        ReturnType::Primitive(Primitive::Double),
+    ).unwrap().d().unwrap();
+    let we: bool = env.get_field_unchecked(
+        conf,
+        f_without_enlargement,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
+    let lr: bool = env.get_field_unchecked(
+        conf,
+        f_linear_resize,
+        ReturnType::Primitive(Primitive::Boolean),
+    ).unwrap().z().unwrap();
 
     let f_subsamp_mode = env.get_field_id(
         cl, "subsamp_mode", "Lcom/luis/bci/CCSParameter$ChromaSubsampling;").unwrap();
@@ -272,17 +800,117 @@ pub unsafe extern "system" fn  Java_com_luis_bci_CaesiumNative_compressPic(
         .unwrap().i()
         .unwrap() as u32;
 
+    let f_compression_profile = env.get_field_id(
+        cl, "compression_profile", "Lcom/luis/bci/CCSParameter$CompressionProfile;").unwrap();
+    let compression_profile_obj = env.get_field_unchecked(
+        conf, f_compression_profile, ReturnType::Object).unwrap().l().unwrap();
+    let compression_profile_ord = env.call_method_unchecked(
+        compression_profile_obj, ordinal_method_id, ReturnType::Primitive(Primitive::Int), &[])
+        .unwrap().i()
+        .unwrap() as u32;
+
+    let f_transform = env.get_field_id(
+        cl, "transform", "Lcom/luis/bci/CCSParameter$Transform;").unwrap();
+    let transform_obj = env.get_field_unchecked(
+        conf, f_transform, ReturnType::Object).unwrap().l().unwrap();
+    let transform_ord = env.call_method_unchecked(
+        transform_obj, ordinal_method_id, ReturnType::Primitive(Primitive::Int), &[])
+        .unwrap().i()
+        .unwrap() as u32;
+
+    let f_grayscale = env.get_field_id(
+        cl, "grayscale", "Lcom/luis/bci/CCSParameter$Grayscale;").unwrap();
+    let grayscale_obj = env.get_field_unchecked(
+        conf, f_grayscale, ReturnType::Object).unwrap().l().unwrap();
+    let grayscale_ord = env.call_method_unchecked(
+        grayscale_obj, ordinal_method_id, ReturnType::Primitive(Primitive::Int), &[])
+        .unwrap().i()
+        .unwrap() as u32;
+
+    let f_resize_fit = env.get_field_id(
+        cl, "resize_fit", "Lcom/luis/bci/CCSParameter$ResizeFit;").unwrap();
+    let resize_fit_obj = env.get_field_unchecked(
+        conf, f_resize_fit, ReturnType::Object).unwrap().l().unwrap();
+    let resize_fit_ord = env.call_method_unchecked(
+        resize_fit_obj, ordinal_method_id, ReturnType::Primitive(Primitive::Int), &[])
+        .unwrap().i()
+        .unwrap() as u32;
+
+    let f_resize_target = env.get_field_id(
+        cl, "resize_target", "Lcom/luis/bci/CCSParameter$ResizeTarget;").unwrap();
+    let resize_target_obj = env.get_field_unchecked(
+        conf, f_resize_target, ReturnType::Object).unwrap().l().unwrap();
+    let resize_target_ord = env.call_method_unchecked(
+        resize_target_obj, ordinal_method_id, ReturnType::Primitive(Primitive::Int), &[])
+        .unwrap().i()
+        .unwrap() as u32;
+
+    let f_resize_filter = env.get_field_id(
+        cl, "resize_filter", "Lcom/luis/bci/CCSParameter$ResizeFilter;").unwrap();
+    let resize_filter_obj = env.get_field_unchecked(
+        conf, f_resize_filter, ReturnType::Object).unwrap().l().unwrap();
+    let resize_filter_ord = env.call_method_unchecked(
+        resize_filter_obj, ordinal_method_id, ReturnType::Primitive(Primitive::Int), &[])
+        .unwrap().i()
+        .unwrap() as u32;
+
+    let f_output_format = env.get_field_id(
+        cl, "output_format", "Lcom/luis/bci/CCSParameter$OutputFormat;").unwrap();
+    let output_format_obj = env.get_field_unchecked(
+        conf, f_output_format, ReturnType::Object).unwrap().l().unwrap();
+    let output_format_ord = env.call_method_unchecked(
+        output_format_obj, ordinal_method_id, ReturnType::Primitive(Primitive::Int), &[])
+        .unwrap().i()
+        .unwrap() as u32;
+
     // Fill parameters
     let params = CCSParameters {
-        keep_metadata: km,
+        keep_icc: kicc,
+        keep_orientation: kori,
+        keep_exif: kexif,
+        keep_gps: kgps,
+        keep_serial_numbers: ksn,
+        keep_maker_notes: kmn,
+        keep_xmp: kxmp,
+        keep_iptc: kiptc,
+        keep_comments: kcom,
+        keep_other_metadata: koth,
         jpeg_quality: jq,
         jpeg_chroma_subsampling: subsamp_mode_ord,
+        jpeg_avoid_generational_loss: agl,
+        jpeg_progressive: prog,
+        jpeg_trellis: tq,
+        jpeg_trellis_dc: tqdc,
+        jpeg_overshoot_deringing: od,
+        jpeg_quant_table: qt,
+        jpeg_compression_profile: compression_profile_ord,
+        jpeg_chroma_quality: jcq,
+        jpeg_transform: transform_ord,
+        jpeg_trim: trim,
+        jpeg_crop_x: cx,
+        jpeg_crop_y: cy,
+        jpeg_crop_width: cw,
+        jpeg_crop_height: ch,
+        jpeg_grayscale: grayscale_ord,
+        jpeg_cmyk_to_rgb: c2r,
         png_quality: pq,
         png_force_zopfli: pfz,
         webp_quality: wq,
         optimize: opt,
         width: ww,
         height: hh,
+        resize_fit: resize_fit_ord,
+        resize_target: resize_target_ord,
+        resize_target_value: rtv,
+        without_enlargement: we,
+        resize_filter: resize_filter_ord,
+        linear_resize: lr,
+        output_size: os,
+        max_dssim: md,
+        output_format: output_format_ord,
+        matte_color: mc,
+        auto_orient: ao,
+        convert_to_srgb: srgb,
     };
 
     // Convert Java byte array to Rust Vec<u8>
@@ -292,6 +920,12 @@ pub unsafe extern "system" fn  Java_com_luis_bci_CaesiumNative_compressPic(
         my_compress(data1, &mut data2, params)
     };
 
+    env.set_field(conf, "source_jpeg_quality", "I", JValue::Int(res.source_jpeg_quality as i32)).unwrap();
+    env.set_field(conf, "applied_crop_x", "I", JValue::Int(res.crop_x as i32)).unwrap();
+    env.set_field(conf, "applied_crop_y", "I", JValue::Int(res.crop_y as i32)).unwrap();
+    env.set_field(conf, "applied_crop_width", "I", JValue::Int(res.crop_width as i32)).unwrap();
+    env.set_field(conf, "applied_crop_height", "I", JValue::Int(res.crop_height as i32)).unwrap();
+
     if res.success {
         log::info!("Compression succeeded with final size = {}!", data2.len());
         env.byte_array_from_slice(&data2).unwrap()
@@ -301,3 +935,53 @@ pub unsafe extern "system" fn  Java_com_luis_bci_CaesiumNative_compressPic(
         std::ptr::null_mut()
     }
 }
+
+/// # Safety
+///
+/// Must only be called by the JVM with a valid `JNIEnv` and byte array.
+#[no_mangle]
+pub unsafe extern "system" fn Java_com_luis_bci_CaesiumNative_analyzePic(
+    env: JNIEnv,
+    _clz: JClass,
+    inBytes: jbyteArray,
+) -> jobject {
+    #[cfg(target_os = "android")]
+    init_logger();
+
+    let data = env.convert_byte_array(inBytes).unwrap();
+    let info = match analyze(&data) {
+        Ok(i) => i,
+        Err(e) => {
+            log::error!("Analysis was not successful because: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let cl = env.find_class("com/luis/bci/CCSImageInfo").unwrap();
+    let obj = env.new_object(cl, "()V", &[]).unwrap();
+
+    let mime_type = env.new_string(info::mime_type(info.file_type)).unwrap();
+    let color_space = env.new_string(info.color_space.name()).unwrap();
+    let chroma_subsampling = match info.chroma_subsampling {
+        Some(cs) => env.new_string(info::subsampling_name(cs)).unwrap().into(),
+        None => JObject::null(),
+    };
+
+    env.set_field(obj, "mime_type", "Ljava/lang/String;", JValue::Object(mime_type.into())).unwrap();
+    env.set_field(obj, "width", "I", JValue::Int(info.width as i32)).unwrap();
+    env.set_field(obj, "height", "I", JValue::Int(info.height as i32)).unwrap();
+    env.set_field(obj, "orientation", "I", JValue::Int(info.orientation as i32)).unwrap();
+    env.set_field(obj, "bit_depth", "I", JValue::Int(info.bit_depth as i32)).unwrap();
+    env.set_field(obj, "color_space", "Ljava/lang/String;", JValue::Object(color_space.into())).unwrap();
+    env.set_field(obj, "chroma_subsampling", "Ljava/lang/String;", JValue::Object(chroma_subsampling)).unwrap();
+    env.set_field(obj, "has_alpha", "Z", JValue::Bool(info.has_alpha as u8)).unwrap();
+    env.set_field(obj, "uses_alpha", "Z", JValue::Bool(info.uses_alpha as u8)).unwrap();
+    env.set_field(obj, "jpeg_quality", "I", JValue::Int(info.jpeg_quality.unwrap_or(0) as i32)).unwrap();
+    env.set_field(obj, "frame_count", "I", JValue::Int(info.frame_count as i32)).unwrap();
+    env.set_field(obj, "icc_size", "I", JValue::Int(info.icc_size as i32)).unwrap();
+    env.set_field(obj, "exif_size", "I", JValue::Int(info.exif_size as i32)).unwrap();
+    env.set_field(obj, "xmp_size", "I", JValue::Int(info.xmp_size as i32)).unwrap();
+    env.set_field(obj, "other_metadata_size", "I", JValue::Int(info.other_metadata_size as i32)).unwrap();
+
+    obj.into_raw()
+}
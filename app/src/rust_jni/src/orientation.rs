@@ -0,0 +1,141 @@
+use image::DynamicImage;
+
+/// Rotations and flips of the image plane, named after jpegtran's `-flip`, `-rotate`,
+/// `-transpose` and `-transverse`. Rotations are clockwise.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Transform {
+    None,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    Transverse,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Transform {
+    /// Transform that turns an image stored with the given EXIF orientation upright.
+    pub fn from_orientation(orientation: u16) -> Transform {
+        match orientation {
+            2 => Transform::FlipHorizontal,
+            3 => Transform::Rotate180,
+            4 => Transform::FlipVertical,
+            5 => Transform::Transpose,
+            6 => Transform::Rotate90,
+            7 => Transform::Transverse,
+            8 => Transform::Rotate270,
+            _ => Transform::None,
+        }
+    }
+
+    /// Single transform equivalent to applying `self` and then `next`.
+    pub fn then(self, next: Transform) -> Transform {
+        let [a, b, c, d] = self.matrix();
+        let [e, f, g, h] = next.matrix();
+        let product = [e * a + f * c, e * b + f * d, g * a + h * c, g * b + h * d];
+
+        ALL.into_iter()
+            .find(|t| t.matrix() == product)
+            .unwrap_or(Transform::None)
+    }
+
+    /// Whether width and height trade places.
+    pub fn swaps_axes(self) -> bool {
+        self.matrix()[0] == 0
+    }
+
+    /// Applies the transform to decoded pixels.
+    pub fn apply(self, image: DynamicImage) -> DynamicImage {
+        match self {
+            Transform::None => image,
+            Transform::FlipHorizontal => image.fliph(),
+            Transform::FlipVertical => image.flipv(),
+            Transform::Transpose => image.rotate90().fliph(),
+            Transform::Transverse => image.rotate270().fliph(),
+            Transform::Rotate90 => image.rotate90(),
+            Transform::Rotate180 => image.rotate180(),
+            Transform::Rotate270 => image.rotate270(),
+        }
+    }
+
+    /// Applies the transform to interleaved 8 bit samples with any number of channels.
+    /// Returns the new buffer with its width and height.
+    pub fn apply_samples(self, buffer: &[u8], width: u32, height: u32, channels: usize) -> (Vec<u8>, u32, u32) {
+        if self == Transform::None {
+            return (buffer.to_vec(), width, height);
+        }
+
+        let (w, h) = (width as usize, height as usize);
+        let (out_width, out_height) = if self.swaps_axes() { (h, w) } else { (w, h) };
+        let mut transformed = Vec::with_capacity(buffer.len());
+        for y in 0..out_height {
+            for x in 0..out_width {
+                // Source pixel of each destination pixel
+                let (sx, sy) = match self {
+                    Transform::None => (x, y),
+                    Transform::FlipHorizontal => (w - 1 - x, y),
+                    Transform::FlipVertical => (x, h - 1 - y),
+                    Transform::Transpose => (y, x),
+                    Transform::Transverse => (w - 1 - y, h - 1 - x),
+                    Transform::Rotate90 => (y, h - 1 - x),
+                    Transform::Rotate180 => (w - 1 - x, h - 1 - y),
+                    Transform::Rotate270 => (w - 1 - y, x),
+                };
+                let offset = (sy * w + sx) * channels;
+                transformed.extend_from_slice(&buffer[offset..offset + channels]);
+            }
+        }
+
+        (transformed, out_width as u32, out_height as u32)
+    }
+
+    /// Where the x and y axes end up, as a row-major 2x2 matrix on centred coordinates
+    /// with y pointing down.
+    fn matrix(self) -> [i8; 4] {
+        match self {
+            Transform::None => [1, 0, 0, 1],
+            Transform::FlipHorizontal => [-1, 0, 0, 1],
+            Transform::FlipVertical => [1, 0, 0, -1],
+            Transform::Transpose => [0, 1, 1, 0],
+            Transform::Transverse => [0, -1, -1, 0],
+            Transform::Rotate90 => [0, -1, 1, 0],
+            Transform::Rotate180 => [-1, 0, 0, -1],
+            Transform::Rotate270 => [0, 1, -1, 0],
+        }
+    }
+}
+
+const ALL: [Transform; 8] = [
+    Transform::None,
+    Transform::FlipHorizontal,
+    Transform::FlipVertical,
+    Transform::Transpose,
+    Transform::Transverse,
+    Transform::Rotate90,
+    Transform::Rotate180,
+    Transform::Rotate270,
+];
+
+#[test]
+fn composition_matches_pixels() {
+    let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(3, 2, |x, y| image::Rgb([x as u8, y as u8, 0])));
+    for first in ALL {
+        for second in ALL {
+            let expected = second.apply(first.apply(image.clone()));
+            let composed = first.then(second).apply(image.clone());
+            assert_eq!(composed.to_rgb8(), expected.to_rgb8());
+        }
+    }
+}
+
+#[test]
+fn samples_match_image() {
+    let image = image::RgbImage::from_fn(3, 2, |x, y| image::Rgb([x as u8, y as u8, 7]));
+    for transform in ALL {
+        let expected = transform.apply(DynamicImage::ImageRgb8(image.clone())).to_rgb8();
+        let (buffer, width, height) = transform.apply_samples(image.as_raw(), 3, 2, 3);
+        assert_eq!((width, height), expected.dimensions());
+        assert_eq!(&buffer, expected.as_raw());
+    }
+}
@@ -1,57 +1,315 @@
 use std::{io, mem};
 use std::num::NonZeroU8;
 
-use image::ImageOutputFormat;
-use lodepng::{decode32, Encoder};
+use image::{DynamicImage, RgbaImage};
+use img_parts::png::{Png, PngChunk};
+use img_parts::{Bytes, ImageICC};
+use lodepng::Encoder;
 use oxipng::Deflaters::{Libdeflater, Zopfli};
+use oxipng::{BitDepth, ColorType, RawImage};
+use rgb::FromSlice;
 
-use crate::CSParameters;
-use crate::resize::resize;
+use crate::{color, exif, icc, CSParameters, MetadataPolicy};
+use crate::icc::KnownProfile;
+use crate::orientation::Transform;
+use crate::resize::{self, resize_image};
+use crate::similarity::Reference;
+use crate::utils::{search_lowest_quality, search_quality};
 
 pub fn compress(
-    in_file: Vec<u8>,
+    mut in_file: Vec<u8>,
     out_buffer: &mut Vec<u8>,
     parameters: &CSParameters,
 ) -> Result<(), io::Error> {
-    if parameters.width > 0 || parameters.height > 0 {
-        let proc_in = resize(in_file, parameters.width, parameters.height, ImageOutputFormat::Png)?;
-        let _ = mem::replace(out_buffer, compress_to_memory(proc_in, parameters)?);
+    // Read before filtering, stripping the orientation must not stop it from being applied
+    let transform = if parameters.auto_orient { orientation(&in_file) } else { Transform::None };
+    // The pixels are converted from their profile whether or not the profile itself is kept
+    let icc = if parameters.convert_to_srgb {
+        Png::from_bytes(in_file.clone().into())
+            .ok()
+            .and_then(|png| png.icc_profile())
+            .filter(|icc| !icc::is_srgb(icc))
     } else {
-        let _ = mem::replace(out_buffer, compress_to_memory(in_file.clone(), parameters)?);
+        None
+    };
+    in_file = filter_chunks(in_file, &parameters.metadata);
+    if parameters.metadata.icc {
+        in_file = compact_icc(in_file);
     }
 
+    let must_resize = resize::dimensions(&in_file).is_some_and(|(width, height)| {
+        let (width, height) = if transform.swaps_axes() { (height, width) } else { (width, height) };
+        resize::is_needed(width, height, parameters)
+    });
+    let lossless_only = parameters.optimize && parameters.output_size == 0 && parameters.max_dssim == 0.0;
+
+    // Untouched pixels are recompressed as they are, anything else is decoded once and encoded from the pixels
+    let compressed = if lossless_only && transform == Transform::None && !must_resize && icc.is_none() {
+        lossless(in_file, parameters)?
+    } else {
+        let (image, mut metadata) = decode_with_metadata(in_file, transform, icc)?;
+        let source = (image.width(), image.height());
+        let image = resize_image(image, parameters)?;
+        for chunk in metadata.iter_mut().filter(|chunk| chunk.kind() == *b"eXIf") {
+            let exif = exif::update_dimensions(chunk.contents(), source, image.width(), image.height());
+            *chunk = PngChunk::new(*b"eXIf", exif.into());
+        }
+        encode(&image, &metadata, parameters)?
+    };
+    let _ = mem::replace(out_buffer, compressed);
+
     Ok(())
 }
 
-pub fn compress_to_memory(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::Error>
-{
-    let optimized_png: Vec<u8> = if parameters.optimize {
-        lossless(in_file, parameters)?
+/// Encodes already decoded pixels, e.g. when converting from another format.
+pub fn compress_image(image: &DynamicImage, parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    encode(image, &[], parameters)
+}
+
+fn encode(image: &DynamicImage, metadata: &[PngChunk], parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    if parameters.output_size > 0 {
+        // Quantizing is only worth its loss when the lossless result does not fit
+        let lossless = lossless_image(image, metadata, parameters)?;
+        if lossless.len() <= parameters.output_size as usize {
+            return Ok(lossless);
+        }
+        compress_to_size(&image.to_rgba8(), metadata, parameters)
+    } else if parameters.max_dssim > 0.0 {
+        compress_to_dssim(&image.to_rgba8(), metadata, parameters)
+    } else if parameters.optimize {
+        lossless_image(image, metadata, parameters)
     } else {
-        lossy(in_file, parameters)?
+        quantize(&image.to_rgba8(), metadata, parameters.png.quality, 256)
+    }
+}
+
+/// The transform that turns the pixels upright according to the eXIf orientation.
+fn orientation(in_file: &[u8]) -> Transform {
+    let orientation = chunks(in_file)
+        .into_iter()
+        .find(|(chunk_type, _)| chunk_type == b"eXIf")
+        .and_then(|(_, data)| exif::orientation(data));
+    Transform::from_orientation(orientation.unwrap_or(1))
+}
+
+/// Drops the metadata chunks `policy` strips and the stripped tags of eXIf. Chunks that affect
+/// rendering or animation are always kept.
+fn filter_chunks(in_file: Vec<u8>, policy: &MetadataPolicy) -> Vec<u8> {
+    if *policy == MetadataPolicy::KEEP_ALL {
+        return in_file;
+    }
+    let mut png = match Png::from_bytes(in_file.clone().into()) {
+        Ok(p) => p,
+        Err(_) => return in_file,
     };
 
-    Ok(optimized_png)
+    let chunks = mem::take(png.chunks_mut());
+    for chunk in chunks {
+        let keep = match &chunk.kind() {
+            b"iCCP" => policy.icc,
+            b"eXIf" => {
+                if let Some(exif) = exif::filter(chunk.contents(), policy) {
+                    png.chunks_mut().push(PngChunk::new(*b"eXIf", exif.into()));
+                }
+                continue;
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" => {
+                let keyword = chunk.contents().split(|b| *b == 0).next().unwrap_or_default();
+                match keyword {
+                    b"XML:com.adobe.xmp" | b"Raw profile type xmp" => policy.xmp,
+                    b"Raw profile type iptc" | b"Raw profile type 8bim" => policy.iptc,
+                    b"Raw profile type exif" | b"Raw profile type APP1" => policy.keeps_all_exif(),
+                    b"Raw profile type icc" | b"Raw profile type icm" => policy.icc,
+                    _ => policy.comments,
+                }
+            }
+            b"tRNS" | b"gAMA" | b"cHRM" | b"sRGB" | b"cICP" | b"sBIT" | b"pHYs" | b"bKGD" | b"hIST" | b"sPLT"
+            | b"acTL" | b"fcTL" | b"fdAT" => true,
+            kind if kind[0].is_ascii_uppercase() => true,
+            _ => policy.other,
+        };
+        if keep {
+            png.chunks_mut().push(chunk);
+        }
+    }
+
+    let mut filtered = vec![];
+    match png.encoder().write_to(&mut filtered) {
+        Ok(_) => filtered,
+        Err(_) => in_file,
+    }
 }
 
-fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
-    let rgba_bitmap = match decode32(in_file) {
-        Ok(i) => i,
-        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+/// Swaps a well-known ICC profile for its compact equivalent, an sRGB profile for the sRGB chunk.
+fn compact_icc(in_file: Vec<u8>) -> Vec<u8> {
+    let mut png = match Png::from_bytes(in_file.clone().into()) {
+        Ok(p) => p,
+        Err(_) => return in_file,
+    };
+    // img_parts expects a NUL terminated profile name
+    let profile = match png.chunk_by_type(*b"iCCP") {
+        Some(chunk) if chunk.contents().contains(&0) => png.icc_profile(),
+        _ => None,
+    };
+    match profile.as_deref().and_then(icc::identify) {
+        Some(KnownProfile::Srgb) => {
+            png.remove_chunks_by_type(*b"iCCP");
+            png.remove_chunks_by_type(*b"sRGB");
+            // Perceptual rendering intent
+            png.chunks_mut().insert(1, PngChunk::new(*b"sRGB", Bytes::from_static(&[0])));
+        }
+        Some(_) => match profile.and_then(|profile| icc::compact(&profile)) {
+            Some(compact) => png.set_icc_profile(Some(compact.into())),
+            None => return in_file,
+        },
+        None => return in_file,
+    }
+
+    let mut compacted = vec![];
+    match png.encoder().write_to(&mut compacted) {
+        Ok(_) => compacted,
+        Err(_) => in_file,
+    }
+}
+
+/// Colour, DPI, EXIF and text chunks to carry over when the pixels are re-encoded. Chunks tied to
+/// the pixel format or palette (tRNS, bKGD, sBIT...) are left to the encoder.
+fn metadata_chunks(in_file: &[u8]) -> Vec<PngChunk> {
+    chunks(in_file)
+        .into_iter()
+        .filter(|(chunk_type, _)| match chunk_type {
+            b"iCCP" | b"sRGB" | b"gAMA" | b"cHRM" | b"cICP" | b"mDCv" | b"cLLi" => true,
+            // Ancillary and safe to copy after the image data changed, e.g. pHYs, eXIf and text
+            _ => chunk_type[0].is_ascii_lowercase() && chunk_type[3].is_ascii_lowercase(),
+        })
+        .map(|(chunk_type, data)| PngChunk::new(chunk_type, Bytes::copy_from_slice(data)))
+        .collect()
+}
+
+/// Inserts `metadata` right after IHDR.
+fn with_metadata(png: Vec<u8>, metadata: &[PngChunk]) -> Vec<u8> {
+    if metadata.is_empty() {
+        return png;
+    }
+    let mut image = match Png::from_bytes(png.clone().into()) {
+        Ok(p) => p,
+        Err(_) => return png,
+    };
+    image.chunks_mut().splice(1..1, metadata.iter().cloned());
+
+    let mut with_metadata = vec![];
+    match image.encoder().write_to(&mut with_metadata) {
+        Ok(_) => with_metadata,
+        Err(_) => png,
+    }
+}
+
+/// Chunks as (type, data) pairs, up to and including IEND.
+pub fn chunks(in_file: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = vec![];
+    if !in_file.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return chunks;
+    }
+
+    let mut pos = 8;
+    while pos + 12 <= in_file.len() {
+        let length = u32::from_be_bytes([in_file[pos], in_file[pos + 1], in_file[pos + 2], in_file[pos + 3]]) as usize;
+        if pos + 12 + length > in_file.len() {
+            break;
+        }
+        let chunk_type = [in_file[pos + 4], in_file[pos + 5], in_file[pos + 6], in_file[pos + 7]];
+        chunks.push((chunk_type, &in_file[pos + 8..pos + 8 + length]));
+        if &chunk_type == b"IEND" {
+            break;
+        }
+        pos += 12 + length;
+    }
+
+    chunks
+}
+
+fn compress_to_size(rgba: &RgbaImage, metadata: &[PngChunk], parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    let max_output_size = parameters.output_size as usize;
+
+    let result = search_quality(0, parameters.png.quality, max_output_size, |quality| {
+        quantize(rgba, metadata, quality, 256)
+    })?;
+    if let Some(compressed) = result {
+        return Ok(compressed);
+    }
+
+    // Even the lowest quality is too big: keep shrinking the palette
+    let result = search_quality(2, 256, max_output_size, |max_colors| {
+        quantize(rgba, metadata, 0, max_colors)
+    })?;
+    match result {
+        Some(compressed) => Ok(compressed),
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Cannot compress PNG to {} bytes", max_output_size),
+        )),
+    }
+}
+
+fn compress_to_dssim(rgba: &RgbaImage, metadata: &[PngChunk], parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    let reference = Reference::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())?;
+
+    search_lowest_quality(0, parameters.png.quality, |quality| {
+        let compressed = quantize(rgba, metadata, quality, 256)?;
+        let dssim = reference.compare(&compressed)?;
+        Ok((compressed, dssim <= parameters.max_dssim))
+    })
+}
+
+/// Decodes the pixels along with the metadata chunks to carry over, turned by `transform`. Pixels
+/// are converted from the `icc` profile to sRGB, which then gives way to an sRGB chunk.
+fn decode_with_metadata(
+    in_file: Vec<u8>,
+    transform: Transform,
+    icc: Option<Bytes>,
+) -> Result<(DynamicImage, Vec<PngChunk>), io::Error> {
+    let mut metadata = metadata_chunks(&in_file);
+
+    let mut image = match image::load_from_memory(&in_file) {
+        Ok(i) => transform.apply(i),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
     };
+    if transform != Transform::None {
+        for chunk in metadata.iter_mut().filter(|chunk| chunk.kind() == *b"eXIf") {
+            let mut exif = chunk.contents().to_vec();
+            exif::reset_orientation(&mut exif);
+            // The thumbnail keeps the old orientation
+            *chunk = PngChunk::new(*b"eXIf", exif::remove_thumbnail(&exif).into());
+        }
+    }
+    if icc.is_some_and(|icc| color::image_to_srgb(&icc, &mut image)) {
+        metadata.retain(|chunk| !matches!(&chunk.kind(), b"iCCP" | b"sRGB" | b"gAMA" | b"cHRM"));
+        // Perceptual rendering intent
+        metadata.insert(0, PngChunk::new(*b"sRGB", Bytes::from_static(&[0])));
+    }
+
+    Ok((image, metadata))
+}
 
+/// Reduces the bitmap to a palette of at most `max_colors` and encodes it together with `metadata`.
+fn quantize(
+    rgba: &RgbaImage,
+    metadata: &[PngChunk],
+    quality: u32,
+    max_colors: u32,
+) -> Result<Vec<u8>, io::Error> {
     let mut liq = imagequant::new();
-    match liq.set_quality(0, parameters.png.quality as u8) {
+    match liq.set_quality(0, quality as u8) {
+        Ok(()) => {}
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    }
+    match liq.set_max_colors(max_colors) {
         Ok(()) => {}
         Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
     }
 
-    let mut liq_image = match liq.new_image(
-        rgba_bitmap.buffer.as_slice(),
-        rgba_bitmap.width,
-        rgba_bitmap.height,
-        0.0,
-    ) {
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+    let mut liq_image = match liq.new_image(rgba.as_raw().as_rgba(), width, height, 0.0) {
         Ok(i) => i,
         Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
     };
@@ -71,21 +329,51 @@ fn lossy(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::Err
         Ok(_) => {}
         Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
     }
-    let png_vec = match encoder.encode(pixels.as_slice(), rgba_bitmap.width, rgba_bitmap.height) {
+    let png_vec = match encoder.encode(pixels.as_slice(), width, height) {
         Ok(pv) => pv,
         Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
     };
 
-    Ok(png_vec)
+    Ok(with_metadata(png_vec, metadata))
 }
 
 fn lossless(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
-    // let in_file = fs::read(input_path)?;
-    let mut oxipng_options = oxipng::Options::default();
-    if !parameters.keep_metadata {
-        oxipng_options.strip = oxipng::Headers::Safe;
-    }
+    // Metadata chunks have been filtered already, oxipng keeps whatever is left
+    let optimized_png = match oxipng::optimize_from_memory(in_file.as_slice(), &oxipng_options(parameters)) {
+        Ok(o) => o,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    };
+
+    Ok(optimized_png)
+}
 
+/// Lossless encoding straight from the pixels, keeping their colour type and bit depth.
+fn lossless_image(image: &DynamicImage, metadata: &[PngChunk], parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    // PNG stores 16 bit samples big endian
+    let big_endian = |samples: &[u16]| samples.iter().flat_map(|s| s.to_be_bytes()).collect::<Vec<u8>>();
+    let (color_type, bit_depth, data) = match image {
+        DynamicImage::ImageLuma8(i) => (ColorType::Grayscale { transparent_shade: None }, BitDepth::Eight, i.to_vec()),
+        DynamicImage::ImageLumaA8(i) => (ColorType::GrayscaleAlpha, BitDepth::Eight, i.to_vec()),
+        DynamicImage::ImageRgb8(i) => (ColorType::RGB { transparent_color: None }, BitDepth::Eight, i.to_vec()),
+        DynamicImage::ImageLuma16(i) => (ColorType::Grayscale { transparent_shade: None }, BitDepth::Sixteen, big_endian(i)),
+        DynamicImage::ImageLumaA16(i) => (ColorType::GrayscaleAlpha, BitDepth::Sixteen, big_endian(i)),
+        DynamicImage::ImageRgb16(i) => (ColorType::RGB { transparent_color: None }, BitDepth::Sixteen, big_endian(i)),
+        DynamicImage::ImageRgba16(i) => (ColorType::RGBA, BitDepth::Sixteen, big_endian(i)),
+        _ => (ColorType::RGBA, BitDepth::Eight, image.to_rgba8().into_raw()),
+    };
+
+    let optimized_png = match RawImage::new(image.width(), image.height(), color_type, bit_depth, data)
+        .and_then(|raw| raw.create_optimized_png(&oxipng_options(parameters)))
+    {
+        Ok(o) => o,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    };
+
+    Ok(with_metadata(optimized_png, metadata))
+}
+
+fn oxipng_options(parameters: &CSParameters) -> oxipng::Options {
+    let mut oxipng_options = oxipng::Options::default();
     if parameters.optimize && parameters.png.force_zopfli {
         oxipng_options.deflate = Zopfli {
             iterations: NonZeroU8::new(15).unwrap(),
@@ -94,11 +382,59 @@ fn lossless(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::
         oxipng_options = oxipng::Options::from_preset(3);
         oxipng_options.deflate = Libdeflater { compression: 6 };
     }
+    oxipng_options
+}
 
-    let optimized_png = match oxipng::optimize_from_memory(in_file.as_slice(), &oxipng_options) {
-        Ok(o) => o,
-        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-    };
+#[cfg(test)]
+fn tagged_with(icc: Vec<u8>) -> Vec<u8> {
+    let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([200, 40, 40])));
+    let mut png = Png::from_bytes(lossless_image(&image, &[], &crate::initialize_parameters()).unwrap().into()).unwrap();
+    png.set_icc_profile(Some(icc.into()));
+    let mut in_file = vec![];
+    png.encoder().write_to(&mut in_file).unwrap();
+    in_file
+}
 
-    Ok(optimized_png)
+#[cfg(test)]
+fn has_iccp(png: &[u8]) -> bool {
+    chunks(png).iter().any(|(chunk_type, _)| chunk_type == b"iCCP")
+}
+
+#[test]
+fn converted_profile_is_stripped() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.metadata = MetadataPolicy::STRIP_ALL;
+    parameters.optimize = true;
+    parameters.convert_to_srgb = true;
+    let mut out_buffer = vec![];
+    compress(tagged_with(KnownProfile::DisplayP3.profile()), &mut out_buffer, &parameters).unwrap();
+
+    assert!(!has_iccp(&out_buffer));
+    let converted = image::load_from_memory(&out_buffer).unwrap().to_rgb8();
+    assert_ne!(converted.get_pixel(0, 0).0, [200, 40, 40]);
+}
+
+#[test]
+fn unusable_profile_follows_the_policy() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.convert_to_srgb = true;
+    for keep_icc in [false, true] {
+        parameters.metadata.icc = keep_icc;
+        let mut out_buffer = vec![];
+        compress(tagged_with(b"Not an ICC profile".to_vec()), &mut out_buffer, &parameters).unwrap();
+        assert_eq!(has_iccp(&out_buffer), keep_icc);
+    }
+}
+
+#[test]
+fn output_size_keeps_lossless_when_it_fits() {
+    let pixels = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, (x + y) as u8]));
+    let mut source = vec![];
+    DynamicImage::ImageRgb8(pixels.clone()).write_to(&mut io::Cursor::new(&mut source), image::ImageFormat::Png).unwrap();
+    let mut parameters = crate::initialize_parameters();
+    parameters.output_size = source.len() as u32 * 2;
+    let mut out_buffer = vec![];
+    compress(source, &mut out_buffer, &parameters).unwrap();
+
+    assert_eq!(image::load_from_memory(&out_buffer).unwrap().to_rgb8(), pixels);
 }
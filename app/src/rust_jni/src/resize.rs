@@ -1,50 +1,346 @@
 use std::io;
 use std::io::Cursor;
+use std::sync::OnceLock;
 
-use image::DynamicImage;
-use image::imageops::FilterType;
+use fast_image_resize::images::{Image, ImageRef};
+use fast_image_resize::{
+    create_srgb_mapper, FilterType, IntoImageView, PixelComponentMapper, PixelType, ResizeAlg, ResizeOptions, Resizer,
+};
+use image::{DynamicImage, GenericImage, GrayImage, ImageBuffer, Luma, LumaA, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
 use image::io::Reader as ImageReader;
 
-#[allow(dead_code)]
-pub fn resize(
-    image_buffer: Vec<u8>,
+use crate::CSParameters;
+
+/// How the image is fitted into the box when both a width and a height are requested.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResizeFit {
+    /// Stretches to the exact box
+    Fill,
+    /// Keeps the aspect ratio and fits inside the box
+    Contain,
+    /// Keeps the aspect ratio, covers the box and crops the overflow around the centre
+    Cover,
+    /// Keeps the aspect ratio and covers the box without cropping
+    Outside,
+    /// Fits inside the box, then pads it out to the exact box around the centre
+    Pad,
+}
+
+/// What the requested size refers to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResizeTarget {
+    /// `width` and `height`, fitted into the box as `resize_fit` says
+    Box,
+    /// Scales the longer side to this many pixels
+    LongEdge(u32),
+    /// Scales the shorter side to this many pixels
+    ShortEdge(u32),
+    /// Scales to this many megapixels
+    Megapixels(f64),
+    /// Scales both sides by this percentage
+    Percentage(f64),
+}
+
+/// Resampling filter, from fastest to sharpest.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResizeFilter {
+    /// Nearest neighbour, blocky but keeps hard edges
+    Nearest,
+    /// Bilinear
+    Triangle,
+    /// Sharp bicubic
+    CatmullRom,
+    /// Softer bicubic with less ringing
+    Mitchell,
+    /// Sharpest, the slowest
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    fn algorithm(self) -> ResizeAlg {
+        match self {
+            ResizeFilter::Nearest => ResizeAlg::Nearest,
+            ResizeFilter::Triangle => ResizeAlg::Convolution(FilterType::Bilinear),
+            ResizeFilter::CatmullRom => ResizeAlg::Convolution(FilterType::CatmullRom),
+            ResizeFilter::Mitchell => ResizeAlg::Convolution(FilterType::Mitchell),
+            ResizeFilter::Lanczos3 => ResizeAlg::Convolution(FilterType::Lanczos3),
+        }
+    }
+}
+
+/// Scaled size of the image and where it lands on the output canvas. Negative offsets
+/// crop the scaled image, positive ones pad it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Placement {
     width: u32,
     height: u32,
-    format: image::ImageOutputFormat,
-) -> Result<Vec<u8>, io::Error> {
-    let mut image = match ImageReader::new(Cursor::new(image_buffer))
-        .with_guessed_format()?
-        .decode()
-    {
-        Ok(i) => i,
-        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
-    };
+    x: i64,
+    y: i64,
+    canvas_width: u32,
+    canvas_height: u32,
+}
 
-    let dimensions = compute_dimensions(image.width(), image.height(), width, height);
-    image = image.resize_exact(dimensions.0, dimensions.1, FilterType::Lanczos3);
+impl Placement {
+    fn scaled(width: u32, height: u32) -> Placement {
+        Placement { width, height, x: 0, y: 0, canvas_width: width, canvas_height: height }
+    }
+
+    fn centred(width: u32, height: u32, canvas_width: u32, canvas_height: u32) -> Placement {
+        Placement {
+            width,
+            height,
+            x: (canvas_width as i64 - width as i64) / 2,
+            y: (canvas_height as i64 - height as i64) / 2,
+            canvas_width,
+            canvas_height,
+        }
+    }
 
-    let mut resized_file: Vec<u8> = vec![];
-    match image.write_to(&mut Cursor::new(&mut resized_file), format) {
-        Ok(_) => {}
-        Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    /// Crops or pads resized pixels to the canvas, `background` fills the padding.
+    fn apply<P: Pixel + 'static>(&self, resized: ImageBuffer<P, Vec<P::Subpixel>>, background: P) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        if self.x < 0 || self.y < 0 {
+            let (x, y) = ((-self.x).max(0) as u32, (-self.y).max(0) as u32);
+            image::imageops::crop_imm(&resized, x, y, self.canvas_width, self.canvas_height).to_image()
+        } else if self.x > 0 || self.y > 0 {
+            let mut canvas = ImageBuffer::from_pixel(self.canvas_width, self.canvas_height, background);
+            // A plain copy, the fourth channel is not always alpha
+            let _ = canvas.copy_from(&resized, self.x as u32, self.y as u32);
+            canvas
+        } else {
+            resized
+        }
     }
+}
 
-    Ok(resized_file)
+/// Width and height from the image header, without decoding the pixels.
+pub fn dimensions(image_buffer: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(image_buffer))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
 }
 
-#[allow(dead_code)]
+/// Whether the parameters change the size of an image that is `width` x `height`.
+pub fn is_needed(width: u32, height: u32, parameters: &CSParameters) -> bool {
+    let requested = parameters.resize_target != ResizeTarget::Box || parameters.width > 0 || parameters.height > 0;
+    requested && place(width, height, parameters) != Placement::scaled(width, height)
+}
+
+/// Resizes to the target of `parameters`, images it does not change are returned as they are.
+/// Padding is transparent when the image has alpha and `parameters.matte_color` otherwise.
 pub fn resize_image(
     image: DynamicImage,
-    width: u32,
-    height: u32,
+    parameters: &CSParameters,
 ) -> Result<DynamicImage, io::Error> {
-    let dimensions = compute_dimensions(image.width(), image.height(), width, height);
-    let resized_image = image.resize_exact(dimensions.0, dimensions.1, FilterType::Lanczos3);
+    if !is_needed(image.width(), image.height(), parameters) {
+        return Ok(image);
+    }
+    let placement = place(image.width(), image.height(), parameters);
+    let resized_image = resize_exact(&image, placement.width, placement.height, Sampling::of(parameters))?;
+    if placement.canvas_width == placement.width && placement.canvas_height == placement.height {
+        return Ok(resized_image);
+    }
+
+    // The canvas keeps the colour type, grayscale is padded with the BT.601 luma of the matte
+    let [r, g, b] = parameters.matte_color;
+    let y = ((299 * r as u32 + 587 * g as u32 + 114 * b as u32 + 500) / 1000) as u8;
+    let wide = |v: u8| v as u16 * 257;
+    Ok(match resized_image {
+        DynamicImage::ImageLuma8(i) => DynamicImage::ImageLuma8(placement.apply(i, Luma([y]))),
+        DynamicImage::ImageLumaA8(i) => DynamicImage::ImageLumaA8(placement.apply(i, LumaA([0, 0]))),
+        DynamicImage::ImageRgb8(i) => DynamicImage::ImageRgb8(placement.apply(i, Rgb([r, g, b]))),
+        DynamicImage::ImageLuma16(i) => DynamicImage::ImageLuma16(placement.apply(i, Luma([wide(y)]))),
+        DynamicImage::ImageLumaA16(i) => DynamicImage::ImageLumaA16(placement.apply(i, LumaA([0, 0]))),
+        DynamicImage::ImageRgb16(i) => DynamicImage::ImageRgb16(placement.apply(i, Rgb([wide(r), wide(g), wide(b)]))),
+        DynamicImage::ImageRgba16(i) => DynamicImage::ImageRgba16(placement.apply(i, Rgba([0, 0, 0, 0]))),
+        i => DynamicImage::ImageRgba8(placement.apply(i.to_rgba8(), Rgba([0, 0, 0, 0]))),
+    })
+}
+
+/// Resizes interleaved 8 bit samples with one, three or four channels, e.g. decoded JPEG grayscale,
+/// RGB or CMYK. `background` fills any padding.
+pub fn resize_samples(
+    buffer: Vec<u8>,
+    original_width: u32,
+    original_height: u32,
+    parameters: &CSParameters,
+    background: &[u8],
+) -> Result<(Vec<u8>, u32, u32), io::Error> {
+    let channels = buffer.len() / (original_width as usize * original_height as usize).max(1);
+    let pixel_type = match channels {
+        1 => PixelType::U8,
+        3 => PixelType::U8x3,
+        4 => PixelType::U8x4,
+        _ => return Err(io::Error::new(io::ErrorKind::Other, "Unsupported number of channels")),
+    };
+    let placement = place(original_width, original_height, parameters);
+    let source = ImageRef::new(original_width, original_height, &buffer, pixel_type).map_err(to_io_error)?;
+    let sampling = if channels == 4 {
+        // The fourth channel is not alpha and the samples are not sRGB encoded, all four are filtered alike
+        Sampling { premultiply: false, linear: false, ..Sampling::of(parameters) }
+    } else {
+        Sampling::of(parameters)
+    };
+    let resized = resize_buffer(&source, pixel_type, &placement, sampling)?;
+
+    let (width, height) = (placement.width, placement.height);
+    let placed = match channels {
+        1 => placement.apply(GrayImage::from_raw(width, height, resized).unwrap(), Luma([background[0]])).into_raw(),
+        3 => {
+            let background = Rgb([background[0], background[1], background[2]]);
+            placement.apply(RgbImage::from_raw(width, height, resized).unwrap(), background).into_raw()
+        }
+        _ => {
+            let background = Rgba([background[0], background[1], background[2], background[3]]);
+            placement.apply(RgbaImage::from_raw(width, height, resized).unwrap(), background).into_raw()
+        }
+    };
+    Ok((placed, placement.canvas_width, placement.canvas_height))
+}
+
+/// Resizes to exactly `width` x `height` with the SIMD resizer, keeping the bit depth. Float images
+/// are resized as 8 bit RGBA.
+fn resize_exact(image: &DynamicImage, width: u32, height: u32, sampling: Sampling) -> Result<DynamicImage, io::Error> {
+    let placement = Placement::scaled(width, height);
+    let (original_width, original_height) = (image.width(), image.height());
+    let resize = |buffer: &[u8], pixel_type| {
+        let source = ImageRef::new(original_width, original_height, buffer, pixel_type).map_err(to_io_error)?;
+        resize_buffer(&source, pixel_type, &placement, sampling)
+    };
+    let resize16 = |buffer: &[u16], pixel_type| -> Result<Vec<u16>, io::Error> {
+        // An owned image keeps the 16 bit samples aligned
+        let mut source = Image::new(original_width, original_height, pixel_type);
+        for (bytes, sample) in source.buffer_mut().chunks_exact_mut(2).zip(buffer) {
+            bytes.copy_from_slice(&sample.to_ne_bytes());
+        }
+        let resized = resize_buffer(&source, pixel_type, &placement, sampling)?;
+        Ok(resized.chunks_exact(2).map(|v| u16::from_ne_bytes([v[0], v[1]])).collect())
+    };
+
+    Ok(match image {
+        DynamicImage::ImageLuma8(i) => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, resize(i, PixelType::U8)?).unwrap()),
+        DynamicImage::ImageLumaA8(i) => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, resize(i, PixelType::U8x2)?).unwrap()),
+        DynamicImage::ImageRgb8(i) => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, resize(i, PixelType::U8x3)?).unwrap()),
+        DynamicImage::ImageRgba8(i) => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, resize(i, PixelType::U8x4)?).unwrap()),
+        DynamicImage::ImageLuma16(i) => DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, resize16(i, PixelType::U16)?).unwrap()),
+        DynamicImage::ImageLumaA16(i) => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, resize16(i, PixelType::U16x2)?).unwrap()),
+        DynamicImage::ImageRgb16(i) => DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, resize16(i, PixelType::U16x3)?).unwrap()),
+        DynamicImage::ImageRgba16(i) => DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, resize16(i, PixelType::U16x4)?).unwrap()),
+        _ => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, resize(&image.to_rgba8(), PixelType::U8x4)?).unwrap()),
+    })
+}
+
+/// How pixels are filtered.
+#[derive(Copy, Clone)]
+struct Sampling {
+    filter: ResizeFilter,
+    /// Premultiplies colour by alpha while filtering, so transparent pixels leave no fringes
+    premultiply: bool,
+    /// Filters linear light instead of sRGB encoded values
+    linear: bool,
+}
+
+impl Sampling {
+    fn of(parameters: &CSParameters) -> Sampling {
+        Sampling { filter: parameters.resize_filter, premultiply: true, linear: parameters.linear_resize }
+    }
+}
+
+/// Resizes interleaved pixels to the scaled size of `placement`.
+fn resize_buffer(
+    source: &impl IntoImageView,
+    pixel_type: PixelType,
+    placement: &Placement,
+    sampling: Sampling,
+) -> Result<Vec<u8>, io::Error> {
+    let options = ResizeOptions::new().resize_alg(sampling.filter.algorithm()).use_alpha(sampling.premultiply);
+    let mut resizer = Resizer::new();
+    let mut destination = Image::new(placement.width, placement.height, pixel_type);
+
+    // 16 bit linear values, 8 would band in the shadows. Alpha is carried over unmapped
+    let linear_type = match pixel_type {
+        PixelType::U8 | PixelType::U16 => Some(PixelType::U16),
+        PixelType::U8x2 | PixelType::U16x2 => Some(PixelType::U16x2),
+        PixelType::U8x3 | PixelType::U16x3 => Some(PixelType::U16x3),
+        PixelType::U8x4 | PixelType::U16x4 => Some(PixelType::U16x4),
+        _ => None,
+    };
+    match linear_type {
+        Some(linear_type) if sampling.linear => {
+            let (width, height) = (source.width(), source.height());
+            let mut linear_source = Image::new(width, height, linear_type);
+            srgb_mapper().forward_map(source, &mut linear_source).map_err(to_io_error)?;
+            let mut linear_destination = Image::new(placement.width, placement.height, linear_type);
+            resizer.resize(&linear_source, &mut linear_destination, &options).map_err(to_io_error)?;
+            srgb_mapper().backward_map(&linear_destination, &mut destination).map_err(to_io_error)?;
+        }
+        _ => resizer.resize(source, &mut destination, &options).map_err(to_io_error)?,
+    }
+    Ok(destination.into_vec())
+}
+
+/// sRGB to linear tables, built on first use.
+fn srgb_mapper() -> &'static PixelComponentMapper {
+    static MAPPER: OnceLock<PixelComponentMapper> = OnceLock::new();
+    MAPPER.get_or_init(create_srgb_mapper)
+}
 
-    Ok(resized_image)
+fn to_io_error(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn place(original_width: u32, original_height: u32, parameters: &CSParameters) -> Placement {
+    let (long_edge, short_edge) = if original_width > original_height {
+        (original_width, original_height)
+    } else {
+        (original_height, original_width)
+    };
+    let scaled = |scale: f64| {
+        Placement::scaled(
+            ((original_width as f64 * scale).round() as u32).max(1),
+            ((original_height as f64 * scale).round() as u32).max(1),
+        )
+    };
+    let placement = match parameters.resize_target {
+        ResizeTarget::Box => fit(original_width, original_height, parameters),
+        ResizeTarget::LongEdge(edge) => scaled(edge as f64 / long_edge as f64),
+        ResizeTarget::ShortEdge(edge) => scaled(edge as f64 / short_edge as f64),
+        ResizeTarget::Megapixels(megapixels) => {
+            scaled((megapixels * 1_000_000.0 / (original_width as f64 * original_height as f64)).sqrt())
+        }
+        ResizeTarget::Percentage(percentage) => scaled(percentage / 100.0),
+    };
+
+    if parameters.without_enlargement && (placement.width > original_width || placement.height > original_height) {
+        Placement::scaled(original_width, original_height)
+    } else {
+        placement
+    }
+}
+
+/// Placement for a width and height box.
+fn fit(original_width: u32, original_height: u32, parameters: &CSParameters) -> Placement {
+    let (width, height) = (parameters.width, parameters.height);
+    let fit = parameters.resize_fit;
+    if width == 0 || height == 0 || fit == ResizeFit::Fill {
+        let (width, height) = compute_dimensions(original_width, original_height, width, height);
+        // A very wide or tall image can round its other side down to nothing
+        return Placement::scaled(width.max(1), height.max(1));
+    }
+
+    let (scale_x, scale_y) = (width as f64 / original_width as f64, height as f64 / original_height as f64);
+    let scale = match fit {
+        ResizeFit::Cover | ResizeFit::Outside => scale_x.max(scale_y),
+        _ => scale_x.min(scale_y),
+    };
+    let scaled_width = ((original_width as f64 * scale).round() as u32).max(1);
+    let scaled_height = ((original_height as f64 * scale).round() as u32).max(1);
+    match fit {
+        ResizeFit::Cover | ResizeFit::Pad => Placement::centred(scaled_width, scaled_height, width, height),
+        _ => Placement::scaled(scaled_width, scaled_height),
+    }
 }
 
-#[allow(dead_code)]
 fn compute_dimensions(
     original_width: u32,
     original_height: u32,
@@ -113,3 +409,130 @@ fn downscale_on_height() {
         (637, 478)
     )
 }
+
+#[test]
+fn fit_modes_keep_aspect_ratio() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.width = 300;
+    parameters.height = 300;
+    let mut fitted = |fit| {
+        parameters.resize_fit = fit;
+        place(800, 600, &parameters)
+    };
+
+    assert_eq!(fitted(ResizeFit::Fill), Placement::scaled(300, 300));
+    assert_eq!(fitted(ResizeFit::Contain), Placement::scaled(300, 225));
+    assert_eq!(fitted(ResizeFit::Outside), Placement::scaled(400, 300));
+    assert_eq!(fitted(ResizeFit::Cover), Placement { width: 400, height: 300, x: -50, y: 0, canvas_width: 300, canvas_height: 300 });
+    assert_eq!(fitted(ResizeFit::Pad), Placement { width: 300, height: 225, x: 0, y: 37, canvas_width: 300, canvas_height: 300 });
+}
+
+#[test]
+fn cover_and_pad_fill_the_box() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.width = 50;
+    parameters.height = 40;
+    for fit in [ResizeFit::Cover, ResizeFit::Pad] {
+        parameters.resize_fit = fit;
+        let images = [
+            DynamicImage::ImageRgb8(image::RgbImage::new(120, 60)),
+            DynamicImage::ImageLuma8(image::GrayImage::new(120, 60)),
+            DynamicImage::ImageRgba16(ImageBuffer::new(120, 60)),
+        ];
+        for image in images {
+            let color = image.color();
+            let resized = resize_image(image, &parameters).unwrap();
+            assert_eq!((resized.width(), resized.height(), resized.color()), (50, 40, color));
+        }
+    }
+}
+
+#[test]
+fn thin_images_keep_a_pixel() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.width = 100;
+    assert_eq!(place(4000, 10, &parameters), Placement::scaled(100, 1));
+}
+
+#[test]
+fn targets_scale_the_whole_image() {
+    let mut parameters = crate::initialize_parameters();
+    let mut placed = |target| {
+        parameters.resize_target = target;
+        place(4000, 3000, &parameters)
+    };
+
+    assert_eq!(placed(ResizeTarget::LongEdge(2000)), Placement::scaled(2000, 1500));
+    assert_eq!(placed(ResizeTarget::ShortEdge(600)), Placement::scaled(800, 600));
+    assert_eq!(placed(ResizeTarget::Megapixels(3.0)), Placement::scaled(2000, 1500));
+    assert_eq!(placed(ResizeTarget::Percentage(25.0)), Placement::scaled(1000, 750));
+}
+
+#[test]
+fn without_enlargement_passes_small_images_through() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.width = 2000;
+    parameters.without_enlargement = true;
+    assert!(!is_needed(300, 300, &parameters));
+    assert!(is_needed(3000, 300, &parameters));
+
+    parameters.without_enlargement = false;
+    assert!(is_needed(300, 300, &parameters));
+}
+
+#[test]
+fn filters_keep_the_bit_depth() {
+    let mut parameters = crate::initialize_parameters();
+    let image = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(90, 60, Rgba([1000, 20000, 65535, 30000])));
+    for filter in [ResizeFilter::Nearest, ResizeFilter::Triangle, ResizeFilter::CatmullRom, ResizeFilter::Mitchell, ResizeFilter::Lanczos3] {
+        parameters.resize_filter = filter;
+        let resized = resize_exact(&image, 30, 20, Sampling::of(&parameters)).unwrap();
+        assert_eq!((resized.width(), resized.height()), (30, 20));
+        let pixel = resized.as_rgba16().unwrap().get_pixel(15, 10).0;
+        // Premultiplying alpha rounds
+        assert!(pixel.iter().zip([1000, 20000, 65535, 30000]).all(|(a, b)| a.abs_diff(b) <= 1));
+    }
+}
+
+#[test]
+fn transparent_pixels_leave_no_fringe() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.width = 8;
+    let image = RgbaImage::from_fn(16, 16, |x, _| if x < 8 { Rgba([0, 0, 0, 0]) } else { Rgba([255, 255, 255, 255]) });
+    let resized = resize_image(DynamicImage::ImageRgba8(image), &parameters).unwrap().to_rgba8();
+    for pixel in resized.pixels().filter(|p| p[3] > 0) {
+        assert!(pixel[0] >= 254, "{:?}", pixel);
+    }
+}
+
+#[test]
+fn linear_resize_keeps_the_brightness() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.width = 8;
+    parameters.resize_filter = ResizeFilter::Triangle;
+    let checkerboard = image::GrayImage::from_fn(16, 16, |x, y| image::Luma([if (x + y) % 2 == 0 { 0 } else { 255 }]));
+    let mut grey = |linear| {
+        parameters.linear_resize = linear;
+        let resized = resize_image(DynamicImage::ImageLuma8(checkerboard.clone()), &parameters).unwrap();
+        resized.to_luma8().get_pixel(4, 4)[0]
+    };
+
+    // Half the light is sRGB 188, not 128
+    assert!(grey(false).abs_diff(128) <= 2);
+    assert!(grey(true).abs_diff(188) <= 2);
+}
+
+#[test]
+fn samples_keep_their_channels() {
+    let mut parameters = crate::initialize_parameters();
+    parameters.width = 10;
+    parameters.height = 10;
+    parameters.resize_fit = ResizeFit::Pad;
+    for (channels, background) in [(1, vec![7]), (3, vec![1, 2, 3]), (4, vec![0, 0, 0, 0])] {
+        let samples = vec![200; 40 * 20 * channels];
+        let (resized, width, height) = resize_samples(samples, 40, 20, &parameters, &background).unwrap();
+        assert_eq!((width, height, resized.len()), (10, 10, 100 * channels));
+        assert_eq!(&resized[..channels], &background[..]);
+        assert_eq!(&resized[50 * channels..51 * channels], &vec![200; channels][..]);
+    }
+}
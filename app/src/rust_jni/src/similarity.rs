@@ -0,0 +1,69 @@
+use std::io;
+
+use dssim_core::{Dssim, DssimImage};
+use image::DynamicImage;
+use rgb::FromSlice;
+
+use crate::orientation::Transform;
+
+/// Uncompressed image that compressed candidates are scored against.
+pub struct Reference {
+    attr: Dssim,
+    image: DssimImage<f32>,
+    width: u32,
+    height: u32,
+}
+
+impl Reference {
+    pub fn from_rgba(pixels: &[u8], width: u32, height: u32) -> Result<Reference, io::Error> {
+        let attr = Dssim::new();
+        let image = match attr.create_image_rgba(pixels.as_rgba(), width as usize, height as usize) {
+            Some(i) => i,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "Cannot create DSSIM reference image")),
+        };
+
+        Ok(Reference {
+            attr,
+            image,
+            width,
+            height,
+        })
+    }
+
+    pub fn from_image(image: &DynamicImage) -> Result<Reference, io::Error> {
+        let rgba = image.to_rgba8();
+        Reference::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())
+    }
+
+    /// Reference for an encoded image as it looks once `transform` is applied.
+    pub fn from_file(in_file: &[u8], transform: Transform) -> Result<Reference, io::Error> {
+        Reference::from_image(&transform.apply(decode(in_file)?))
+    }
+
+    /// DSSIM between the reference and an encoded image, 0 meaning identical.
+    pub fn compare(&self, compressed: &[u8]) -> Result<f64, io::Error> {
+        let rgba = decode(compressed)?.to_rgba8();
+        if rgba.width() != self.width || rgba.height() != self.height {
+            return Err(io::Error::new(io::ErrorKind::Other, "Compared images have different dimensions"));
+        }
+
+        let modified = match self.attr.create_image_rgba(
+            rgba.as_raw().as_rgba(),
+            self.width as usize,
+            self.height as usize,
+        ) {
+            Some(i) => i,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "Cannot create DSSIM image")),
+        };
+        let (dssim, _) = self.attr.compare(&self.image, modified);
+
+        Ok(dssim.into())
+    }
+}
+
+fn decode(in_file: &[u8]) -> Result<DynamicImage, io::Error> {
+    match image::load_from_memory(in_file) {
+        Ok(i) => Ok(i),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    }
+}
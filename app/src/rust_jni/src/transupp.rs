@@ -0,0 +1,70 @@
+//! Bindings for the lossless transformation helpers in mozjpeg's `transupp.c`.
+//!
+//! `mozjpeg-sys` compiles `transupp.c` with the `jpegtran` feature, but its `jpeg_transform_info`
+//! predates the drop/wipe fields of the vendored header, so the struct is declared here to match it.
+
+#![allow(non_camel_case_types)]
+
+use mozjpeg_sys::{boolean, c_int, j_compress_ptr, j_decompress_ptr, jvirt_barray_ptr, JDIMENSION};
+
+pub type JXFORM_CODE = std::os::raw::c_uint;
+pub const JXFORM_NONE: JXFORM_CODE = 0;
+pub const JXFORM_FLIP_H: JXFORM_CODE = 1;
+pub const JXFORM_FLIP_V: JXFORM_CODE = 2;
+pub const JXFORM_TRANSPOSE: JXFORM_CODE = 3;
+pub const JXFORM_TRANSVERSE: JXFORM_CODE = 4;
+pub const JXFORM_ROT_90: JXFORM_CODE = 5;
+pub const JXFORM_ROT_180: JXFORM_CODE = 6;
+pub const JXFORM_ROT_270: JXFORM_CODE = 7;
+
+pub type JCROP_CODE = std::os::raw::c_uint;
+pub const JCROP_POS: JCROP_CODE = 1;
+
+/// Mirrors `jpeg_transform_info` from the vendored `transupp.h`.
+#[repr(C)]
+pub struct jpeg_transform_info {
+    pub transform: JXFORM_CODE,
+    pub perfect: boolean,
+    pub trim: boolean,
+    pub force_grayscale: boolean,
+    pub crop: boolean,
+    pub slow_hflip: boolean,
+    pub crop_width: JDIMENSION,
+    pub crop_width_set: JCROP_CODE,
+    pub crop_height: JDIMENSION,
+    pub crop_height_set: JCROP_CODE,
+    pub crop_xoffset: JDIMENSION,
+    pub crop_xoffset_set: JCROP_CODE,
+    pub crop_yoffset: JDIMENSION,
+    pub crop_yoffset_set: JCROP_CODE,
+    pub drop_ptr: j_decompress_ptr,
+    pub drop_coef_arrays: *mut jvirt_barray_ptr,
+    pub num_components: c_int,
+    pub workspace_coef_arrays: *mut jvirt_barray_ptr,
+    pub output_width: JDIMENSION,
+    pub output_height: JDIMENSION,
+    pub x_crop_offset: JDIMENSION,
+    pub y_crop_offset: JDIMENSION,
+    pub drop_width: JDIMENSION,
+    pub drop_height: JDIMENSION,
+    pub iMCU_sample_width: c_int,
+    pub iMCU_sample_height: c_int,
+}
+
+extern "C" {
+    pub fn jtransform_request_workspace(srcinfo: j_decompress_ptr, info: *mut jpeg_transform_info) -> boolean;
+
+    pub fn jtransform_adjust_parameters(
+        srcinfo: j_decompress_ptr,
+        dstinfo: j_compress_ptr,
+        src_coef_arrays: *mut jvirt_barray_ptr,
+        info: *mut jpeg_transform_info,
+    ) -> *mut jvirt_barray_ptr;
+
+    pub fn jtransform_execute_transform(
+        srcinfo: j_decompress_ptr,
+        dstinfo: j_compress_ptr,
+        src_coef_arrays: *mut jvirt_barray_ptr,
+        info: *mut jpeg_transform_info,
+    );
+}
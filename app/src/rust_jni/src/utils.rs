@@ -1,5 +1,8 @@
+use std::io;
+
 use infer::Infer;
 
+#[derive(Copy, Clone, PartialEq)]
 pub enum SupportedFileTypes {
     Jpeg,
     Png,
@@ -25,4 +28,100 @@ pub fn get_filetype(data: Vec<u8>) -> SupportedFileTypes {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Binary searches `min_quality..=max_quality` for the highest quality whose output fits
+/// in `max_output_size` bytes. Returns `None` when even `min_quality` is too big.
+/// Any setting where a higher value means a bigger output (e.g. palette size) can be searched.
+pub fn search_quality<F>(
+    min_quality: u32,
+    max_quality: u32,
+    max_output_size: usize,
+    mut compress: F,
+) -> Result<Option<Vec<u8>>, io::Error>
+where
+    F: FnMut(u32) -> Result<Vec<u8>, io::Error>,
+{
+    // Most images already fit at the requested quality, so try that first
+    let compressed = compress(max_quality)?;
+    if compressed.len() <= max_output_size {
+        return Ok(Some(compressed));
+    }
+
+    let mut best = None;
+    let mut low = min_quality;
+    let mut high = max_quality;
+    while low < high {
+        let quality = low + (high - low) / 2;
+        let compressed = compress(quality)?;
+        if compressed.len() <= max_output_size {
+            best = Some(compressed);
+            low = quality + 1;
+        } else {
+            high = quality;
+        }
+    }
+
+    Ok(best)
+}
+
+/// Binary searches `min_quality..=max_quality` for the lowest quality whose output `compress`
+/// reports as acceptable. Falls back to the `max_quality` output when nothing is acceptable.
+pub fn search_lowest_quality<F>(
+    min_quality: u32,
+    max_quality: u32,
+    mut compress: F,
+) -> Result<Vec<u8>, io::Error>
+where
+    F: FnMut(u32) -> Result<(Vec<u8>, bool), io::Error>,
+{
+    let (mut best, acceptable) = compress(max_quality)?;
+    if !acceptable {
+        return Ok(best);
+    }
+
+    let mut low = min_quality;
+    let mut high = max_quality;
+    while low < high {
+        let quality = low + (high - low) / 2;
+        let (compressed, acceptable) = compress(quality)?;
+        if acceptable {
+            best = compressed;
+            high = quality;
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    Ok(best)
+}
+
+#[test]
+fn search_quality_highest_fit() {
+    let result = search_quality(1, 100, 42, |q| Ok(vec![0; q as usize])).unwrap();
+    assert_eq!(result.map(|r| r.len()), Some(42))
+}
+
+#[test]
+fn search_quality_requested_fits() {
+    let result = search_quality(1, 80, 1000, |q| Ok(vec![0; q as usize])).unwrap();
+    assert_eq!(result.map(|r| r.len()), Some(80))
+}
+
+#[test]
+fn search_quality_unreachable() {
+    let result = search_quality(10, 100, 5, |q| Ok(vec![0; q as usize])).unwrap();
+    assert!(result.is_none())
+}
+
+#[test]
+fn search_lowest_quality_threshold() {
+    let result = search_lowest_quality(1, 100, |q| Ok((vec![0; q as usize], q >= 37))).unwrap();
+    assert_eq!(result.len(), 37)
+}
+
+#[test]
+fn search_lowest_quality_unreachable() {
+    let result = search_lowest_quality(1, 80, |q| Ok((vec![0; q as usize], false))).unwrap();
+    assert_eq!(result.len(), 80)
+}
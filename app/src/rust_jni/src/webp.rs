@@ -1,46 +1,296 @@
 use std::{io, mem};
 use std::ops::Deref;
 
-use crate::CSParameters;
-use crate::resize::resize_image;
+use image::DynamicImage;
+
+use crate::{color, exif, icc, CSParameters};
+use crate::orientation::Transform;
+use crate::resize::{is_needed, resize_image};
+use crate::similarity::Reference;
+use crate::utils::{search_lowest_quality, search_quality};
+
+const VP8X_ICC: u8 = 0x20;
+const VP8X_ALPHA: u8 = 0x10;
+const VP8X_EXIF: u8 = 0x08;
+const VP8X_XMP: u8 = 0x04;
+const VP8X_ANIMATION: u8 = 0x02;
+
+/// ICC profile, EXIF and XMP payloads of a WebP container.
+#[derive(Clone, Default)]
+pub struct Metadata {
+    pub icc: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    pub fn read(in_file: &[u8]) -> Metadata {
+        let mut metadata = Metadata::default();
+        for (fourcc, data) in chunks(in_file) {
+            match &fourcc {
+                b"ICCP" => metadata.icc = Some(data.to_vec()),
+                // The container stores bare TIFF, some writers add the JPEG APP1 prefix anyway
+                b"EXIF" => metadata.exif = Some(data.strip_prefix(b"Exif\0\0").unwrap_or(data).to_vec()),
+                b"XMP " => metadata.xmp = Some(data.to_vec()),
+                _ => {}
+            }
+        }
+
+        metadata
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.icc.is_none() && self.exif.is_none() && self.xmp.is_none()
+    }
+}
 
 pub fn compress(
     in_file: Vec<u8>,
     out_buffer: &mut Vec<u8>,
     parameters: &CSParameters,
 ) -> Result<(), io::Error> {
-    let _ = mem::replace(out_buffer, compress_to_memory(in_file, parameters)?);
+    let mut metadata = Metadata::read(&in_file);
+    let mut compressed = compress_to_memory(&in_file, &mut metadata, parameters)?;
+    if parameters.metadata.keeps_any() {
+        let policy = &parameters.metadata;
+        let metadata = Metadata {
+            icc: metadata.icc.filter(|_| policy.icc).map(|profile| icc::compact(&profile).unwrap_or(profile)),
+            exif: metadata.exif.and_then(|exif| exif::filter(&exif, policy)).map(|mut exif| {
+                if parameters.auto_orient && matches!(exif::reset_orientation(&mut exif), Some(2..=8)) {
+                    exif = exif::remove_thumbnail(&exif);
+                }
+                exif
+            }),
+            xmp: metadata.xmp.filter(|_| policy.xmp),
+        };
+        let source = canvas(&chunks(&in_file)).map_or((0, 0), |(width, height, _)| (width, height));
+        compressed = write_metadata(compressed, &metadata, source);
+    }
+
+    let _ = mem::replace(out_buffer, compressed);
     Ok(())
 }
 
-pub fn compress_to_memory(in_file: Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, io::Error>
+/// Re-encodes the pixels of `in_file`. When they are converted to sRGB the ICC profile is
+/// removed from `metadata`.
+pub fn compress_to_memory(in_file: &[u8], metadata: &mut Metadata, parameters: &CSParameters) -> Result<Vec<u8>, io::Error>
 {
-    let decoder = webp::Decoder::new(&in_file);
-    let input_webp = match decoder.decode() {
-        Some(img) => img,
-        None => return Err(io::Error::new(io::ErrorKind::Other, "WebP decode failed!")),
-    };
-    let mut input_image = input_webp.to_image();
-    let must_resize = parameters.width > 0 || parameters.height > 0;
+    let mut input_image = decode(in_file)?;
+    if parameters.convert_to_srgb {
+        if let Some(icc) = metadata.icc.as_deref().filter(|icc| !icc::is_srgb(icc)) {
+            if color::image_to_srgb(icc, &mut input_image) {
+                metadata.icc = None;
+            }
+        }
+    }
+    if parameters.auto_orient {
+        let orientation = chunks(in_file)
+            .into_iter()
+            .find(|(fourcc, _)| fourcc == b"EXIF")
+            .and_then(|(_, data)| exif::orientation(data));
+        input_image = Transform::from_orientation(orientation.unwrap_or(1)).apply(input_image);
+    }
+    let must_resize = is_needed(input_image.width(), input_image.height(), parameters);
     if must_resize {
-        input_image = resize_image(input_image, parameters.width, parameters.height)?;
+        input_image = resize_image(input_image, parameters)?;
+    }
+
+    //TODO With resize lossless encoding can throw an error
+    encode(&input_image, parameters, parameters.optimize && !must_resize)
+}
+
+/// Encodes already decoded pixels, e.g. when converting from another format.
+pub fn compress_image(image: &DynamicImage, parameters: &CSParameters) -> Result<Vec<u8>, io::Error> {
+    // The encoder only takes 8 bit RGB and RGBA
+    let input_image = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    encode(&input_image, parameters, parameters.optimize)
+}
+
+pub fn decode(in_file: &[u8]) -> Result<DynamicImage, io::Error> {
+    let decoder = webp::Decoder::new(in_file);
+    match decoder.decode() {
+        Some(img) => Ok(img.to_image()),
+        None => Err(io::Error::new(io::ErrorKind::Other, "WebP decode failed!")),
+    }
+}
+
+/// Re-muxes `webp` as an extended (VP8X) file carrying `metadata` in place of any it had, with the
+/// EXIF dimensions updated to the ones of the canvas. `source` is the size of the image the EXIF comes from.
+pub fn write_metadata(webp: Vec<u8>, metadata: &Metadata, source: (u32, u32)) -> Vec<u8> {
+    let chunks = chunks(&webp);
+    if metadata.is_empty() {
+        return webp;
+    }
+    let (width, height, flags) = match canvas(&chunks) {
+        Some(c) => c,
+        None => return webp,
+    };
+
+    let exif = metadata.exif.as_ref().map(|exif| exif::update_dimensions(exif, source, width, height));
+    let mut flags = flags & (VP8X_ALPHA | VP8X_ANIMATION);
+    if metadata.icc.is_some() {
+        flags |= VP8X_ICC;
+    }
+    if exif.is_some() {
+        flags |= VP8X_EXIF;
+    }
+    if metadata.xmp.is_some() {
+        flags |= VP8X_XMP;
     }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend(&(height - 1).to_le_bytes()[..3]);
 
-    let encoder = match webp::Encoder::from_image(&input_image) {
+    // Chunk order required by the container spec
+    let mut body = b"WEBP".to_vec();
+    push_chunk(&mut body, b"VP8X", &vp8x);
+    if let Some(icc) = &metadata.icc {
+        push_chunk(&mut body, b"ICCP", icc);
+    }
+    for (fourcc, data) in &chunks {
+        if !matches!(fourcc, b"VP8X" | b"ICCP" | b"EXIF" | b"XMP ") {
+            push_chunk(&mut body, fourcc, data);
+        }
+    }
+    if let Some(exif) = &exif {
+        push_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        push_chunk(&mut body, b"XMP ", xmp);
+    }
+
+    let mut muxed = b"RIFF".to_vec();
+    muxed.extend((body.len() as u32).to_le_bytes());
+    muxed.extend(body);
+    muxed
+}
+
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend(fourcc);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() & 1 == 1 {
+        out.push(0);
+    }
+}
+
+/// Canvas width and height with the alpha and animation VP8X flags, from the first chunk.
+fn canvas(chunks: &[([u8; 4], &[u8])]) -> Option<(u32, u32, u8)> {
+    let (fourcc, data) = chunks.first()?;
+    match fourcc {
+        b"VP8X" if data.len() >= 10 => Some((
+            u32::from_le_bytes([data[4], data[5], data[6], 0]) + 1,
+            u32::from_le_bytes([data[7], data[8], data[9], 0]) + 1,
+            data[0],
+        )),
+        b"VP8 " if data.len() >= 10 => Some((
+            (u16::from_le_bytes([data[6], data[7]]) & 0x3FFF) as u32,
+            (u16::from_le_bytes([data[8], data[9]]) & 0x3FFF) as u32,
+            0,
+        )),
+        b"VP8L" if data.len() >= 5 => {
+            let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            let alpha = if (bits >> 28) & 1 != 0 { VP8X_ALPHA } else { 0 };
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, alpha))
+        }
+        _ => None,
+    }
+}
+
+/// Top level RIFF chunks as (FourCC, payload) pairs.
+pub fn chunks(in_file: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    if in_file.len() < 12 || &in_file[0..4] != b"RIFF" || &in_file[8..12] != b"WEBP" {
+        return vec![];
+    }
+
+    sub_chunks(&in_file[12..])
+}
+
+/// Chunks packed one after the other, e.g. the frame data inside an ANMF chunk.
+pub fn sub_chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = vec![];
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        if pos + 8 + size > data.len() {
+            break;
+        }
+        let fourcc = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+        chunks.push((fourcc, &data[pos + 8..pos + 8 + size]));
+        // Chunks are padded to an even size
+        pos += 8 + size + (size & 1);
+    }
+
+    chunks
+}
+
+fn encode(input_image: &DynamicImage, parameters: &CSParameters, lossless: bool) -> Result<Vec<u8>, io::Error> {
+    let encoder = match webp::Encoder::from_image(input_image) {
         Ok(encoder) => encoder,
         Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
     };
 
-    let encoded_image = if parameters.optimize {
-        if must_resize {
-            encoder.encode(parameters.webp.quality as f32)
-        } else {
-            //TODO With resize can throw an error
-            encoder.encode_lossless()
-        }
+    if parameters.output_size > 0 {
+        let max_output_size = parameters.output_size as usize;
+        let result = search_quality(0, parameters.webp.quality, max_output_size, |quality| {
+            Ok(encoder.encode(quality as f32).deref().to_vec())
+        })?;
+        return match result {
+            Some(compressed) => Ok(compressed),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Cannot compress WebP to {} bytes", max_output_size),
+            )),
+        };
+    }
+
+    if parameters.max_dssim > 0.0 {
+        let reference = Reference::from_image(input_image)?;
+        return search_lowest_quality(0, parameters.webp.quality, |quality| {
+            let compressed = encoder.encode(quality as f32).deref().to_vec();
+            let dssim = reference.compare(&compressed)?;
+            Ok((compressed, dssim <= parameters.max_dssim))
+        });
+    }
+
+    let encoded_image = if lossless {
+        encoder.encode_lossless()
     } else {
         encoder.encode(parameters.webp.quality as f32)
     };
 
     Ok(encoded_image.deref().to_vec())
 }
+
+#[test]
+fn metadata_round_trip() {
+    // 3x2 VP8L header with the alpha bit set, the bitstream itself does not matter here
+    let bits: u32 = 2 | (1 << 14) | (1 << 28);
+    let mut vp8l = vec![0x2F];
+    vp8l.extend(bits.to_le_bytes());
+    let mut webp = b"RIFF".to_vec();
+    webp.extend((4 + 8 + 6u32).to_le_bytes());
+    webp.extend(b"WEBP");
+    push_chunk(&mut webp, b"VP8L", &vp8l);
+
+    let metadata = Metadata {
+        icc: Some(vec![1, 2, 3]),
+        exif: None,
+        xmp: Some(b"<x/>".to_vec()),
+    };
+    let muxed = write_metadata(webp, &metadata, (3, 2));
+    let fourccs: Vec<[u8; 4]> = chunks(&muxed).into_iter().map(|(fourcc, _)| fourcc).collect();
+    assert_eq!(fourccs, [*b"VP8X", *b"ICCP", *b"VP8L", *b"XMP "]);
+    assert_eq!(canvas(&chunks(&muxed)), Some((3, 2, VP8X_ICC | VP8X_ALPHA | VP8X_XMP)));
+    assert_eq!(u32::from_le_bytes(muxed[4..8].try_into().unwrap()) as usize, muxed.len() - 8);
+
+    let read = Metadata::read(&muxed);
+    assert_eq!(read.icc, metadata.icc);
+    assert_eq!(read.xmp, metadata.xmp);
+    assert_eq!(read.exif, None);
+}